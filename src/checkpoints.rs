@@ -0,0 +1,97 @@
+use std::cell::RefCell;
+use std::time::Duration;
+
+use ic_cdk::api::time;
+
+use candid::{CandidType, Deserialize};
+
+use crate::localkey::refcell::with;
+use crate::MemoryId;
+use crate::stable_memory_tools::{write_checkpoint, caller_is_controller_gaurd, reply_result};
+use crate::timer_schedules::{TimerSchedules, set_schedule_interval, update_schedule_state};
+
+
+struct CheckpointState {
+    last_checkpoint_timestamp_nanos: Option<u64>,
+    last_checkpoint_error: Option<String>,
+}
+
+thread_local!{
+    static CHECKPOINTS: RefCell<TimerSchedules<CheckpointState>> = const { RefCell::new(TimerSchedules::new()) };
+}
+
+
+/// Checkpoints the global variable of the memory-id into its upgrade slots every `interval`, 
+/// so that an upgrade with `skip_pre_upgrade` loads a recent payload instead of the payload of the last upgrade.
+/// `None` stops the checkpoints of the memory-id.
+///
+/// A checkpoint serializes the data the same way as the [pre_upgrade](crate::pre_upgrade), 
+/// into the slot that does not hold the newest payload. 
+/// The timers do not survive an upgrade, call this function after the memory-id is registered, 
+/// in the canister_init method and in the post_upgrade hook.
+///
+/// Needs the `checkpoints` cargo feature.
+///
+/// ## Sample
+/// ```ignore
+/// #[post_upgrade]
+/// fn post_upgrade() {
+///     canister_tools::post_upgrade(&DATA, DATA_UPGRADE_MEMORY_ID, None::<fn(Data) -> Data>);
+///     canister_tools::set_checkpoint_interval(DATA_UPGRADE_MEMORY_ID, Some(Duration::from_secs(60 * 60)));
+/// }
+/// ```
+pub fn set_checkpoint_interval(memory_id: MemoryId, opt_interval: Option<Duration>) {
+    set_schedule_interval(&CHECKPOINTS, memory_id, opt_interval, checkpoint, |opt_old_state| {
+        CheckpointState {
+            last_checkpoint_timestamp_nanos: opt_old_state.and_then(|c| c.last_checkpoint_timestamp_nanos),
+            last_checkpoint_error: None,
+        }
+    });
+}
+
+fn checkpoint(memory_id: MemoryId) {
+    // an error is recorded instead of trapping, the timer keeps running.
+    let result: Result<(), String> = write_checkpoint(memory_id);
+    update_schedule_state(&CHECKPOINTS, memory_id, |c| {
+        match result {
+            Ok(()) => {
+                c.last_checkpoint_timestamp_nanos = Some(time());
+                c.last_checkpoint_error = None;
+            }
+            Err(e) => {
+                c.last_checkpoint_error = Some(e);
+            }
+        }
+    });
+}
+
+
+/// The checkpoint schedule of a memory-id, returned by the `controller_checkpoints` canister method.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CheckpointStatus {
+    pub memory_id: u8,
+    pub interval_seconds: u64,
+    /// `None` when there was no successful checkpoint since the interval was set or since the last upgrade.
+    pub last_checkpoint_timestamp_nanos: Option<u64>,
+    /// The error of the last checkpoint, `None` when the last checkpoint succeeded.
+    pub last_checkpoint_error: Option<String>,
+}
+
+
+#[export_name = "canister_query controller_checkpoints"]
+extern "C" fn controller_checkpoints() {
+    caller_is_controller_gaurd();
+    
+    with(&CHECKPOINTS, |checkpoints| {
+        reply_result::<Vec<CheckpointStatus>>(Ok(
+            checkpoints.values().map(|c| {
+                CheckpointStatus {
+                    memory_id: c.memory_id,
+                    interval_seconds: c.interval.as_secs(),
+                    last_checkpoint_timestamp_nanos: c.state.last_checkpoint_timestamp_nanos,
+                    last_checkpoint_error: c.state.last_checkpoint_error.clone(),
+                }
+            }).collect(),
+        ));
+    });
+}
@@ -0,0 +1,113 @@
+// The chunks of a snapshot upload with the controller_put_state_snapshot_chunk canister method.
+// Each chunk is put at its index, so a chunk that is put twice replaces itself, and the chunks can arrive in any order.
+// The chunks are joined into the snapshot when the last missing chunk arrives.
+
+use std::collections::BTreeMap;
+
+use crate::ControllerError;
+use crate::header::sha256;
+
+
+pub(crate) struct ChunkedUpload {
+    chunk_count: u32,
+    // the chunks that are not joined yet.
+    chunks: BTreeMap<u32, Vec<u8>>,
+    // the sha256 of each chunk that arrived, kept after the join to check the chunks that arrive again.
+    chunk_sha256s: BTreeMap<u32, [u8; 32]>,
+}
+
+impl ChunkedUpload {
+    pub(crate) fn new(chunk_count: u32) -> Self {
+        Self {
+            chunk_count,
+            chunks: BTreeMap::new(),
+            chunk_sha256s: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn chunk_count(&self) -> u32 {
+        self.chunk_count
+    }
+
+    pub(crate) fn received_chunks(&self) -> u32 {
+        self.chunk_sha256s.len() as u32
+    }
+
+    pub(crate) fn is_complete(&self) -> bool {
+        self.received_chunks() == self.chunk_count
+    }
+
+    pub(crate) fn missing_chunks(&self) -> Vec<u32> {
+        (0..self.chunk_count).filter(|i| !self.chunk_sha256s.contains_key(i)).collect()
+    }
+
+    /// Puts the chunk at its index.
+    /// Once the upload is complete, a chunk that arrives again must be the same as the first time.
+    pub(crate) fn put_chunk(&mut self, chunk_index: u32, chunk_count: u32, bytes: Vec<u8>) -> Result<(), ControllerError> {
+        if chunk_count != self.chunk_count {
+            return Err(ControllerError::ChunkCountMismatch{ chunk_count: self.chunk_count });
+        }
+        if chunk_index >= self.chunk_count {
+            return Err(ControllerError::ChunkIndexOutOfRange{ chunk_index, chunk_count });
+        }
+        let chunk_sha256: [u8; 32] = sha256(&bytes);
+        if self.is_complete() {
+            if self.chunk_sha256s.get(&chunk_index) != Some(&chunk_sha256) {
+                return Err(ControllerError::ChunkMismatch{ chunk_index });
+            }
+            return Ok(());
+        }
+        self.chunk_sha256s.insert(chunk_index, chunk_sha256);
+        self.chunks.insert(chunk_index, bytes);
+        Ok(())
+    }
+
+    /// Joins the chunks in the order of their indexes, drops each chunk as it is joined.
+    pub(crate) fn take_joined_chunks(&mut self) -> Vec<u8> {
+        let mut b: Vec<u8> = Vec::with_capacity(self.chunks.values().map(|chunk| chunk.len()).sum());
+        while let Some((_, chunk)) = self.chunks.pop_first() {
+            b.extend_from_slice(&chunk);
+        }
+        b
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn put_chunks_out_of_order() {
+        let mut upload = ChunkedUpload::new(3);
+        upload.put_chunk(2, 3, vec![5, 6]).unwrap();
+        // a chunk that is put twice before the upload is complete replaces itself.
+        upload.put_chunk(0, 3, vec![9]).unwrap();
+        upload.put_chunk(0, 3, vec![1, 2]).unwrap();
+        assert_eq!(upload.missing_chunks(), vec![1]);
+        assert!(!upload.is_complete());
+        upload.put_chunk(1, 3, vec![3, 4]).unwrap();
+        assert!(upload.is_complete());
+        assert_eq!(upload.take_joined_chunks(), vec![1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn put_chunk_with_the_wrong_count_or_index() {
+        let mut upload = ChunkedUpload::new(2);
+        assert_eq!(upload.put_chunk(0, 3, vec![1]), Err(ControllerError::ChunkCountMismatch{ chunk_count: 2 }));
+        assert_eq!(upload.put_chunk(2, 2, vec![1]), Err(ControllerError::ChunkIndexOutOfRange{ chunk_index: 2, chunk_count: 2 }));
+        assert_eq!(upload.received_chunks(), 0);
+    }
+
+    #[test]
+    fn put_chunk_again_after_the_join() {
+        let mut upload = ChunkedUpload::new(2);
+        upload.put_chunk(0, 2, vec![1, 2]).unwrap();
+        upload.put_chunk(1, 2, vec![3]).unwrap();
+        assert_eq!(upload.take_joined_chunks(), vec![1, 2, 3]);
+        // a retry of a chunk that already arrived.
+        assert_eq!(upload.put_chunk(1, 2, vec![3]), Ok(()));
+        assert_eq!(upload.put_chunk(1, 2, vec![4]), Err(ControllerError::ChunkMismatch{ chunk_index: 1 }));
+        assert_eq!(upload.take_joined_chunks(), Vec::<u8>::new());
+    }
+}
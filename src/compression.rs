@@ -0,0 +1,163 @@
+use std::io::{Read, Write};
+
+use candid::{CandidType, Deserialize};
+
+
+/// The compression of an upgrade payload or of a state-snapshot. Recorded in the stable-memory header.
+///
+/// Each compression is behind a cargo feature: `deflate`, `lz4`, `zstd`.
+/// Set the compression of a memory-id with [set_compression](crate::set_compression).
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    /// [Deflate](https://docs.rs/flate2/latest/flate2/index.html) with the pure rust backend.
+    Deflate,
+    /// The [lz4](https://docs.rs/lz4_flex/latest/lz4_flex/index.html) frame format.
+    Lz4,
+    /// [Zstandard](https://docs.rs/zstd/latest/zstd/index.html). Building for wasm32 needs a clang with the wasm32 target.
+    Zstd,
+}
+
+impl Compression {
+    pub(crate) fn id(&self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Deflate => 1,
+            Compression::Lz4 => 2,
+            Compression::Zstd => 3,
+        }
+    }
+    pub(crate) fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Deflate),
+            2 => Some(Compression::Lz4),
+            3 => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+    /// Whether the cargo feature of this compression is enabled.
+    pub fn is_enabled(&self) -> bool {
+        match self {
+            Compression::None => true,
+            Compression::Deflate => cfg!(feature = "deflate"),
+            Compression::Lz4 => cfg!(feature = "lz4"),
+            Compression::Zstd => cfg!(feature = "zstd"),
+        }
+    }
+    fn not_enabled_error(&self) -> String {
+        format!("the {:?} compression is not enabled, enable its cargo feature", self)
+    }
+}
+
+
+/// Runs `f` with a writer that compresses into `w`, then finishes the compression and returns `w`.
+pub(crate) fn compress_into<W: Write>(compression: Compression, w: W, f: &mut dyn FnMut(&mut dyn Write) -> Result<(), String>) -> Result<W, String> {
+    match compression {
+        Compression::None => {
+            let mut w = w;
+            f(&mut w)?;
+            Ok(w)
+        }
+        #[cfg(feature = "deflate")]
+        Compression::Deflate => {
+            let mut encoder = flate2::write::DeflateEncoder::new(w, flate2::Compression::default());
+            f(&mut encoder)?;
+            encoder.finish().map_err(|e| format!("{:?}", e))
+        }
+        #[cfg(feature = "lz4")]
+        Compression::Lz4 => {
+            let mut encoder = lz4_flex::frame::FrameEncoder::new(w);
+            f(&mut encoder)?;
+            encoder.finish().map_err(|e| format!("{:?}", e))
+        }
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => {
+            let mut encoder = zstd::stream::write::Encoder::new(w, zstd::DEFAULT_COMPRESSION_LEVEL).map_err(|e| format!("{:?}", e))?;
+            f(&mut encoder)?;
+            encoder.finish().map_err(|e| format!("{:?}", e))
+        }
+        #[allow(unreachable_patterns)]
+        c => Err(c.not_enabled_error()),
+    }
+}
+
+/// Returns a reader that decompresses `r`.
+pub(crate) fn decompress_from<'a, R: Read + 'a>(compression: Compression, r: R) -> Result<Box<dyn Read + 'a>, String> {
+    match compression {
+        Compression::None => Ok(Box::new(r)),
+        #[cfg(feature = "deflate")]
+        Compression::Deflate => Ok(Box::new(flate2::read::DeflateDecoder::new(r))),
+        #[cfg(feature = "lz4")]
+        Compression::Lz4 => Ok(Box::new(lz4_flex::frame::FrameDecoder::new(r))),
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => Ok(Box::new(zstd::stream::read::Decoder::new(r).map_err(|e| format!("{:?}", e))?)),
+        #[allow(unreachable_patterns)]
+        c => Err(c.not_enabled_error()),
+    }
+}
+
+pub(crate) fn compress_bytes(compression: Compression, b: &[u8]) -> Result<Vec<u8>, String> {
+    compress_into(compression, Vec::new(), &mut |w| w.write_all(b).map_err(|e| format!("{:?}", e)))
+}
+
+pub(crate) fn decompress_bytes(compression: Compression, b: &[u8]) -> Result<Vec<u8>, String> {
+    let mut v: Vec<u8> = Vec::new();
+    decompress_from(compression, b)?.read_to_end(&mut v).map_err(|e| format!("{:?}", e))?;
+    Ok(v)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMPRESSIONS: [Compression; 4] = [Compression::None, Compression::Deflate, Compression::Lz4, Compression::Zstd];
+
+    fn test_bytes() -> Vec<u8> {
+        (0..100_000_u32).map(|i| (i % 17) as u8).collect()
+    }
+
+    #[test]
+    fn round_trip_each_enabled_compression() {
+        for compression in COMPRESSIONS.into_iter().filter(|c| c.is_enabled()) {
+            let b: Vec<u8> = compress_bytes(compression, &test_bytes()).unwrap();
+            if compression != Compression::None {
+                assert!(b.len() < test_bytes().len() / 10, "{:?}", compression);
+            }
+            assert!(decompress_bytes(compression, &b).unwrap() == test_bytes(), "{:?}", compression);
+        }
+    }
+
+    #[test]
+    fn compress_into_a_writer_in_parts() {
+        for compression in COMPRESSIONS.into_iter().filter(|c| c.is_enabled()) {
+            let b: Vec<u8> = compress_into(compression, Vec::new(), &mut |w| {
+                for part in test_bytes().chunks(999) {
+                    w.write_all(part).map_err(|e| format!("{:?}", e))?;
+                }
+                Ok(())
+            }).unwrap();
+            let mut v: Vec<u8> = Vec::new();
+            decompress_from(compression, &b[..]).unwrap().read_to_end(&mut v).unwrap();
+            assert!(v == test_bytes(), "{:?}", compression);
+        }
+    }
+
+    #[test]
+    fn compression_that_is_not_enabled() {
+        for compression in COMPRESSIONS.into_iter().filter(|c| !c.is_enabled()) {
+            assert_eq!(compress_bytes(compression, &test_bytes()), Err(compression.not_enabled_error()));
+            assert_eq!(decompress_bytes(compression, &test_bytes()), Err(compression.not_enabled_error()));
+        }
+    }
+
+    #[test]
+    fn compression_ids() {
+        for compression in COMPRESSIONS {
+            assert_eq!(Compression::from_id(compression.id()), Some(compression));
+        }
+        assert_eq!(Compression::from_id(4), None);
+    }
+}
@@ -0,0 +1,159 @@
+use std::fmt;
+
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::memory_manager::MemoryId;
+
+use crate::{SerializationFormat, Compression};
+
+
+/// The error returned by [try_post_upgrade](crate::try_post_upgrade).
+///
+/// When the error is returned, the global variable is not changed, the memory-id is not registered,
+/// and the payload in the stable memory of the memory-id is left as is.
+/// The payload is overwritten the next time a global variable is registered with the memory-id.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UpgradeError {
+    /// There is nothing stored in the stable memory of the memory-id.
+    EmptyMemory { memory_id: MemoryId },
+    /// The header region holds data but the magic bytes are not found.
+    MissingHeader { memory_id: MemoryId },
+    /// The header was written with a stable-memory layout that this version of the library does not know.
+    UnknownLayoutVersion { memory_id: MemoryId, layout_version: u32 },
+    /// The header records a different serialization format than the format of the type being loaded.
+    FormatMismatch { memory_id: MemoryId, stored_format_id: u8, expected_format: SerializationFormat },
+    /// The stored payload length can't be converted into an in-memory length,
+    /// or it is bigger than the stable memory that holds it.
+    Conversion { memory_id: MemoryId, error: String },
+    /// The header records a different payload length than the length stored with the payload.
+    LengthMismatch { memory_id: MemoryId, header_length: u64, stored_length: u64 },
+    /// The sha256 of the payload does not match the sha256 in the header.
+    ChecksumMismatch { memory_id: MemoryId },
+    /// The payload is compressed with an unknown compression, or with a compression whose cargo feature is not enabled.
+    Decompression { memory_id: MemoryId, error: String },
+    /// [Serializable::backward](crate::Serializable::backward) returned an error.
+    /// `written_by_same_type` is `None` when the payload has no header.
+    Decode { memory_id: MemoryId, error: String, written_by_same_type: Option<bool> },
+    /// The header records a data version that is not in the [Migrations](crate::Migrations) chain. 
+    /// The payloads loaded with [post_upgrade](crate::post_upgrade) must be at data version 0.
+    UnknownDataVersion { memory_id: MemoryId, data_version: u32 },
+    /// The memory-id is already registered with a global variable.
+    AlreadyRegistered { memory_id: MemoryId },
+    /// The memory-id is reserved by the canister-tools library, see [ROLLBACK_POINTS_MEMORY_ID](crate::ROLLBACK_POINTS_MEMORY_ID), [NAMED_SNAPSHOTS_MEMORY_ID](crate::NAMED_SNAPSHOTS_MEMORY_ID), and [UPLOAD_STAGING_MEMORY_ID](crate::UPLOAD_STAGING_MEMORY_ID).
+    ReservedMemoryId { memory_id: MemoryId },
+}
+
+impl fmt::Display for UpgradeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpgradeError::EmptyMemory{ memory_id } => write!(f, "memory-id: {:?}: the stable memory is empty, there is no header and no payload", memory_id),
+            UpgradeError::MissingHeader{ memory_id } => write!(f, "memory-id: {:?}: the stable memory header is missing, the magic bytes are not found", memory_id),
+            UpgradeError::UnknownLayoutVersion{ memory_id, layout_version } => write!(f, "memory-id: {:?}: unknown stable memory header layout version: {}", memory_id, layout_version),
+            UpgradeError::FormatMismatch{ memory_id, stored_format_id, expected_format } => write!(
+                f,
+                "memory-id: {:?}: the payload is serialized with the format {}, the type being loaded uses the format {:?}",
+                memory_id,
+                SerializationFormat::from_id(*stored_format_id).map(|sf| format!("{:?}", sf)).unwrap_or(format!("id: {}", stored_format_id)),
+                expected_format
+            ),
+            UpgradeError::Conversion{ memory_id, error } => write!(f, "memory-id: {:?}: error reading the payload: {}", memory_id, error),
+            UpgradeError::LengthMismatch{ memory_id, header_length, stored_length } => write!(f, "memory-id: {:?}: payload length mismatch, the header records {} bytes, the stable memory holds {} bytes", memory_id, header_length, stored_length),
+            UpgradeError::ChecksumMismatch{ memory_id } => write!(f, "memory-id: {:?}: checksum mismatch, the sha256 of the payload does not match the sha256 recorded in the header", memory_id),
+            UpgradeError::Decompression{ memory_id, error } => write!(f, "memory-id: {:?}: error decompressing the payload: {}", memory_id, error),
+            UpgradeError::Decode{ memory_id, error, written_by_same_type } => {
+                let hint = match written_by_same_type {
+                    Some(false) => "the payload was written by a different type than the type being loaded",
+                    Some(true) => "the payload was written by the same type that is being loaded",
+                    None => "the payload has no header",
+                };
+                write!(f, "memory-id: {:?}: error deserializing the payload, {}: {}", memory_id, hint, error)
+            }
+            UpgradeError::UnknownDataVersion{ memory_id, data_version } => write!(f, "memory-id: {:?}: the payload is at data version {}, there is no migration for this data version", memory_id, data_version),
+            UpgradeError::AlreadyRegistered{ memory_id } => write!(f, "memory-id: {:?} is already registered with the canister-tools library.", memory_id),
+            UpgradeError::ReservedMemoryId{ memory_id } => write!(f, "memory-id: {:?} is reserved by the canister-tools library.", memory_id),
+        }
+    }
+}
+
+impl std::error::Error for UpgradeError {}
+
+
+/// The error returned by the `controller_*` canister methods, in the `Err` case of their `variant { Ok; Err: ControllerError }` reply.
+///
+/// When a method returns an error, the method does not change the state of the canister.
+/// An expired upload session is dropped before the method runs, with its snapshot buffer and its staged bytes,
+/// so that drop happens even when the method then returns an error.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum ControllerError {
+    /// The memory-id is not registered with a global variable, or it is not a valid memory-id.
+    UnknownMemoryId { memory_id: u8 },
+    /// The range `offset..offset+length` is not within the `size` bytes of the snapshot or of the stable memory.
+    OutOfRange { offset: u64, length: u64, size: u64 },
+    /// The requested length is longer than the bytes that fit in one reply, see [MAX_REPLY_BLOB_LENGTH](crate::MAX_REPLY_BLOB_LENGTH).
+    ReplyTooLarge { length: u64, max_length: u64 },
+    /// The cargo feature of the compression is not enabled on this canister.
+    CompressionNotEnabled { compression: Compression },
+    /// The global variable fails to serialize.
+    Serialization(String),
+    /// The snapshot fails to compress or to decompress.
+    Compression(String),
+    /// The snapshot fails to deserialize as the registered type.
+    Decode(String),
+    /// The snapshot does not pass the invariant checks.
+    InvariantChecks(Vec<String>),
+    /// There is no rollback point stored for the memory-id.
+    NoRollbackPoint,
+    /// There is no named snapshot with the label.
+    NamedSnapshotNotFound { label: String },
+    /// The label is longer than [NAMED_SNAPSHOT_LABEL_MAX_LENGTH](crate::NAMED_SNAPSHOT_LABEL_MAX_LENGTH) bytes.
+    LabelTooLong { max_length: u64 },
+    /// The chunk index is not less than the chunk count of the upload.
+    ChunkIndexOutOfRange { chunk_index: u32, chunk_count: u32 },
+    /// The chunk count is different than the chunk count of the upload in progress, clear the snapshot to start a new upload.
+    ChunkCountMismatch { chunk_count: u32 },
+    /// The upload is complete and the chunk is different than the chunk that arrived at the same index.
+    ChunkMismatch { chunk_index: u32 },
+    /// Not all of the chunks of the upload arrived.
+    IncompleteUpload { received_chunks: u32, chunk_count: u32 },
+    /// An upload session holds the snapshot buffer of the memory-id, wait for the session to end or cancel the session.
+    UploadSessionInProgress { session_id: u64, expires_at_nanos: u64 },
+    /// There is no upload session with the session-id, or the session expired.
+    UnknownUploadSession { session_id: u64 },
+    /// Not all of the bytes of the upload session arrived.
+    IncompleteUploadSession { received_length: u64, total_length: u64 },
+    /// The sha256 of the uploaded bytes does not match the sha256 declared at the begin of the upload session.
+    UploadChecksumMismatch { session_id: u64 },
+    /// The sha256 is not 32 bytes long.
+    InvalidSha256 { length: u64 },
+    /// The stable memory can't grow by the pages.
+    StableMemoryGrowFailed { pages: u64 },
+}
+
+impl fmt::Display for ControllerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ControllerError::UnknownMemoryId{ memory_id } => write!(f, "memory-id: {} is not registered with the canister-tools library", memory_id),
+            ControllerError::OutOfRange{ offset, length, size } => write!(f, "the range {}..{} is out of the {} bytes", offset, offset.saturating_add(*length), size),
+            ControllerError::ReplyTooLarge{ length, max_length } => write!(f, "the length {} is more than the {} bytes that fit in one reply", length, max_length),
+            ControllerError::CompressionNotEnabled{ compression } => write!(f, "the {:?} compression is not enabled on this canister", compression),
+            ControllerError::Serialization(e) => write!(f, "error serializing the global variable: {}", e),
+            ControllerError::Compression(e) => write!(f, "error compressing or decompressing the snapshot: {}", e),
+            ControllerError::Decode(e) => write!(f, "error deserializing the snapshot: {}", e),
+            ControllerError::InvariantChecks(failed_checks) => write!(f, "the snapshot does not pass the invariant checks: {}", failed_checks.join("; ")),
+            ControllerError::NoRollbackPoint => write!(f, "there is no rollback point for this memory-id"),
+            ControllerError::NamedSnapshotNotFound{ label } => write!(f, "there is no named snapshot with the label: {}", label),
+            ControllerError::LabelTooLong{ max_length } => write!(f, "the label is longer than {} bytes", max_length),
+            ControllerError::ChunkIndexOutOfRange{ chunk_index, chunk_count } => write!(f, "the chunk index {} is out of the {} chunks of the upload", chunk_index, chunk_count),
+            ControllerError::ChunkCountMismatch{ chunk_count } => write!(f, "the upload in progress has {} chunks, clear the snapshot to start a new upload", chunk_count),
+            ControllerError::ChunkMismatch{ chunk_index } => write!(f, "the upload is complete and the chunk {} is different than the chunk that arrived before", chunk_index),
+            ControllerError::IncompleteUpload{ received_chunks, chunk_count } => write!(f, "the upload is incomplete, {} of the {} chunks arrived", received_chunks, chunk_count),
+            ControllerError::UploadSessionInProgress{ session_id, expires_at_nanos } => write!(f, "the upload session {} holds the snapshot buffer, the session expires at {} nanoseconds", session_id, expires_at_nanos),
+            ControllerError::UnknownUploadSession{ session_id } => write!(f, "there is no upload session {}, or the session expired", session_id),
+            ControllerError::IncompleteUploadSession{ received_length, total_length } => write!(f, "the upload session is incomplete, {} of the {} bytes arrived", received_length, total_length),
+            ControllerError::UploadChecksumMismatch{ session_id } => write!(f, "the sha256 of the bytes of the upload session {} does not match the declared sha256", session_id),
+            ControllerError::InvalidSha256{ length } => write!(f, "the sha256 is {} bytes long instead of 32 bytes", length),
+            ControllerError::StableMemoryGrowFailed{ pages } => write!(f, "the stable memory can't grow by {} wasm pages", pages),
+        }
+    }
+}
+
+impl std::error::Error for ControllerError {}
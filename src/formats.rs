@@ -0,0 +1,247 @@
+//! Wrappers that serialize a global variable with a format other than candid. 
+//! Each format is behind a cargo feature: `cbor`, `bincode`, `msgpack`.
+//!
+//! Wrap the type of the global variable, the wrapper derefs into the inner type.
+//! ```ignore
+//! thread_local! {
+//!     static DATA: RefCell<Cbor<Data>> = RefCell::new(Cbor(Data::default()));
+//! }
+//! ```
+//! Or keep the type as is and register it with the format's [UpgradeCodec](crate::UpgradeCodec). 
+//! This way the type can also implement `CandidType` for the canister methods.
+//! ```ignore
+//! canister_tools::init_with_codec::<Data, CborCodec>(&DATA, DATA_UPGRADE_MEMORY_ID);
+//! ```
+
+#[cfg(any(feature = "cbor", feature = "bincode", feature = "msgpack"))]
+use std::io::{Read, Write};
+
+#[cfg(any(feature = "cbor", feature = "bincode", feature = "msgpack"))]
+use serde::{Serialize, de::DeserializeOwned};
+
+#[cfg(any(feature = "cbor", feature = "bincode", feature = "msgpack"))]
+use crate::{Serializable, SerializationFormat, UpgradeCodec};
+
+
+#[cfg(any(feature = "cbor", feature = "bincode", feature = "msgpack"))]
+macro_rules! format_wrapper {
+    ($(#[$doc:meta])* $name:ident) => {
+        $(#[$doc])*
+        #[derive(Clone, Debug, Default, PartialEq, Eq)]
+        pub struct $name<T>(pub T);
+        
+        impl<T> $name<T> {
+            pub fn into_inner(self) -> T {
+                self.0
+            }
+        }
+        
+        impl<T> std::ops::Deref for $name<T> {
+            type Target = T;
+            fn deref(&self) -> &T {
+                &self.0
+            }
+        }
+        
+        impl<T> std::ops::DerefMut for $name<T> {
+            fn deref_mut(&mut self) -> &mut T {
+                &mut self.0
+            }
+        }
+        
+        impl<T> From<T> for $name<T> {
+            fn from(t: T) -> Self {
+                Self(t)
+            }
+        }
+    };
+}
+
+// Implements Serializable for the wrapper through the format's codec.
+#[cfg(any(feature = "cbor", feature = "bincode", feature = "msgpack"))]
+macro_rules! serializable_through_codec {
+    ($name:ident, $codec:ident) => {
+        impl<T: Serialize + DeserializeOwned> Serializable for $name<T> {
+            fn forward(&self) -> Result<Vec<u8>, String> {
+                <$codec as UpgradeCodec<T>>::forward(&self.0)
+            }
+            fn backward(b: &[u8]) -> Result<Self, String> {
+                <$codec as UpgradeCodec<T>>::backward(b).map(Self)
+            }
+            fn forward_into(&self, w: &mut dyn Write) -> Result<(), String> {
+                <$codec as UpgradeCodec<T>>::forward_into(&self.0, w)
+            }
+            fn backward_from(r: &mut dyn Read) -> Result<Self, String> {
+                <$codec as UpgradeCodec<T>>::backward_from(r).map(Self)
+            }
+            fn format() -> SerializationFormat {
+                <$codec as UpgradeCodec<T>>::format()
+            }
+        }
+    };
+}
+
+
+#[cfg(feature = "cbor")]
+format_wrapper!(
+    /// Serializes the inner type with [CBOR](https://docs.rs/ciborium/latest/ciborium/index.html). Streams into and out of the stable memory on upgrades.
+    Cbor
+);
+
+/// The [UpgradeCodec] for [CBOR](https://docs.rs/ciborium/latest/ciborium/index.html).
+#[cfg(feature = "cbor")]
+pub struct CborCodec;
+
+#[cfg(feature = "cbor")]
+impl<T: Serialize + DeserializeOwned> UpgradeCodec<T> for CborCodec {
+    fn forward(data: &T) -> Result<Vec<u8>, String> {
+        let mut b: Vec<u8> = Vec::new();
+        ciborium::into_writer(data, &mut b).map_err(|e| format!("{:?}", e))?;
+        Ok(b)
+    }
+    fn backward(b: &[u8]) -> Result<T, String> {
+        ciborium::from_reader(b).map_err(|e| format!("{:?}", e))
+    }
+    fn forward_into(data: &T, w: &mut dyn Write) -> Result<(), String> {
+        ciborium::into_writer(data, w).map_err(|e| format!("{:?}", e))
+    }
+    fn backward_from(r: &mut dyn Read) -> Result<T, String> {
+        ciborium::from_reader(r).map_err(|e| format!("{:?}", e))
+    }
+    fn format() -> SerializationFormat {
+        SerializationFormat::Cbor
+    }
+}
+
+#[cfg(feature = "cbor")]
+serializable_through_codec!(Cbor, CborCodec);
+
+
+#[cfg(feature = "bincode")]
+format_wrapper!(
+    /// Serializes the inner type with [bincode](https://docs.rs/bincode/1/bincode/index.html). Streams into and out of the stable memory on upgrades.
+    ///
+    /// Bincode is not self-describing, a field added to the type needs a migration, see [Migrations](crate::Migrations).
+    Bincode
+);
+
+/// The [UpgradeCodec] for [bincode](https://docs.rs/bincode/1/bincode/index.html).
+#[cfg(feature = "bincode")]
+pub struct BincodeCodec;
+
+#[cfg(feature = "bincode")]
+impl<T: Serialize + DeserializeOwned> UpgradeCodec<T> for BincodeCodec {
+    fn forward(data: &T) -> Result<Vec<u8>, String> {
+        bincode::serialize(data).map_err(|e| format!("{:?}", e))
+    }
+    fn backward(b: &[u8]) -> Result<T, String> {
+        bincode::deserialize(b).map_err(|e| format!("{:?}", e))
+    }
+    fn forward_into(data: &T, w: &mut dyn Write) -> Result<(), String> {
+        bincode::serialize_into(w, data).map_err(|e| format!("{:?}", e))
+    }
+    fn backward_from(r: &mut dyn Read) -> Result<T, String> {
+        bincode::deserialize_from(r).map_err(|e| format!("{:?}", e))
+    }
+    fn format() -> SerializationFormat {
+        SerializationFormat::Bincode
+    }
+}
+
+#[cfg(feature = "bincode")]
+serializable_through_codec!(Bincode, BincodeCodec);
+
+
+#[cfg(feature = "msgpack")]
+format_wrapper!(
+    /// Serializes the inner type with [MessagePack](https://docs.rs/rmp-serde/latest/rmp_serde/index.html). Streams into and out of the stable memory on upgrades.
+    ///
+    /// Structs are serialized as maps with the field names.
+    MessagePack
+);
+
+/// The [UpgradeCodec] for [MessagePack](https://docs.rs/rmp-serde/latest/rmp_serde/index.html).
+#[cfg(feature = "msgpack")]
+pub struct MessagePackCodec;
+
+#[cfg(feature = "msgpack")]
+impl<T: Serialize + DeserializeOwned> UpgradeCodec<T> for MessagePackCodec {
+    fn forward(data: &T) -> Result<Vec<u8>, String> {
+        rmp_serde::to_vec_named(data).map_err(|e| format!("{:?}", e))
+    }
+    fn backward(b: &[u8]) -> Result<T, String> {
+        rmp_serde::from_slice(b).map_err(|e| format!("{:?}", e))
+    }
+    fn forward_into(data: &T, w: &mut dyn Write) -> Result<(), String> {
+        rmp_serde::encode::write_named(w, data).map_err(|e| format!("{:?}", e))
+    }
+    fn backward_from(r: &mut dyn Read) -> Result<T, String> {
+        rmp_serde::decode::from_read(r).map_err(|e| format!("{:?}", e))
+    }
+    fn format() -> SerializationFormat {
+        SerializationFormat::MessagePack
+    }
+}
+
+#[cfg(feature = "msgpack")]
+serializable_through_codec!(MessagePack, MessagePackCodec);
+
+
+#[cfg(all(test, any(feature = "cbor", feature = "bincode", feature = "msgpack")))]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    
+    #[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default, PartialEq)]
+    struct TestData {
+        field_one: String,
+        field_two: BTreeMap<u64, Vec<u8>>,
+    }
+    
+    fn test_data() -> TestData {
+        TestData {
+            field_one: "Hi World".to_string(),
+            field_two: (0..100).map(|i| (i, vec![i as u8; i as usize])).collect(),
+        }
+    }
+    
+    // The wrapper and the codec write the same bytes, in one piece and streamed.
+    macro_rules! format_tests {
+        ($test_name:ident, $name:ident, $codec:ident, $format:expr) => {
+            #[test]
+            fn $test_name() {
+                let data = $name(test_data());
+                let b: Vec<u8> = data.forward().unwrap();
+                assert_eq!($name::<TestData>::backward(&b).unwrap(), data);
+                assert_eq!(<$codec as UpgradeCodec<TestData>>::forward(&data).unwrap(), b);
+                
+                let mut streamed: Vec<u8> = Vec::new();
+                data.forward_into(&mut streamed).unwrap();
+                assert_eq!(streamed, b);
+                assert_eq!($name::<TestData>::backward_from(&mut &streamed[..]).unwrap(), data);
+                
+                assert_eq!($name::<TestData>::format(), $format);
+                assert_eq!(<$codec as UpgradeCodec<TestData>>::format(), $format);
+                assert!($name::<TestData>::backward(&b[..b.len() / 2]).is_err());
+            }
+        };
+    }
+    
+    #[cfg(feature = "cbor")]
+    format_tests!(cbor_round_trip, Cbor, CborCodec, SerializationFormat::Cbor);
+    
+    #[cfg(feature = "bincode")]
+    format_tests!(bincode_round_trip, Bincode, BincodeCodec, SerializationFormat::Bincode);
+    
+    #[cfg(feature = "msgpack")]
+    format_tests!(message_pack_round_trip, MessagePack, MessagePackCodec, SerializationFormat::MessagePack);
+    
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn wrapper_derefs_into_the_inner_type() {
+        let mut data: Cbor<TestData> = test_data().into();
+        data.field_one.push('!');
+        assert_eq!(data.field_one, "Hi World!");
+        assert_eq!(data.into_inner().field_two.len(), 100);
+    }
+}
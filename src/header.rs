@@ -0,0 +1,370 @@
+// The header that is written into the first STABLE_MEMORY_HEADER_SIZE_BYTES of each upgrade memory-id.
+//
+// Layout (all integers big-endian):
+//
+//   0..8      magic bytes
+//   8..12     layout version
+//   12        commit record: the slot of the last written payload
+//   16..112   slot 0 payload descriptor
+//   112..208  slot 1 payload descriptor
+//   512..768  slot 0 migration log
+//   768..1024 slot 1 migration log
+//
+// Payload descriptor:
+//
+//   0       serialization format id
+//   1       compression id
+//   8..16   payload length, after the compression
+//   16..48  sha256 of the payload, after the compression
+//   48..56  type fingerprint
+//   56..64  timestamp (nanoseconds since the unix epoch)
+//   64..68  data version
+//   72..80  payload offset, the payload is stored at this offset after an 8-byte length
+//   80..88  sequence number, zero for an empty slot
+//
+// Migration log:
+//
+//   0..2    migration log length
+//   8..     migration log, 16 bytes per record: from-version (4), to-version (4), timestamp (8)
+//
+// Each slot has its own migration log, so that the older slot loads with the migration log of its payload.
+//
+// Unused bytes are zero.
+
+use candid::{CandidType, Deserialize};
+use sha2::{Sha256, Digest};
+
+use ic_stable_structures::{
+    Memory,
+    DefaultMemoryImpl,
+    memory_manager::{MemoryId, VirtualMemory},
+};
+
+use crate::{STABLE_MEMORY_HEADER_SIZE_BYTES, UpgradeError};
+
+
+const HEADER_MAGIC: [u8; 8] = *b"CNSTRTLS";
+
+/// The version of the stable-memory layout that this version of the library writes.
+pub const HEADER_LAYOUT_VERSION: u32 = 1;
+
+const PAYLOAD_DESCRIPTOR_START: usize = 16;
+const PAYLOAD_DESCRIPTOR_SIZE: usize = 96;
+const MIGRATION_LOG_START: usize = 512;
+const MIGRATION_LOG_SIZE: usize = 256;
+const MIGRATION_LOG_RECORDS_START: usize = 8;
+const MIGRATION_RECORD_SIZE: usize = 16;
+
+/// The number of migration records that fit in the header for each payload slot. When the log is full the oldest records are dropped.
+pub const MIGRATION_LOG_CAPACITY: usize = (MIGRATION_LOG_SIZE - MIGRATION_LOG_RECORDS_START) / MIGRATION_RECORD_SIZE;
+
+
+/// The serialization format of an upgrade payload. Recorded in the stable-memory header.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SerializationFormat {
+    /// A hand-written [Serializable](crate::Serializable) implementation.
+    Custom,
+    /// The [candid](https://docs.rs/candid/latest/candid/index.html) binary format.
+    Candid,
+    /// CBOR, see [Cbor](crate::formats).
+    Cbor,
+    /// Bincode, see [Bincode](crate::formats).
+    Bincode,
+    /// MessagePack, see [MessagePack](crate::formats).
+    MessagePack,
+}
+
+impl SerializationFormat {
+    pub(crate) fn id(&self) -> u8 {
+        match self {
+            SerializationFormat::Custom => 0,
+            SerializationFormat::Candid => 1,
+            SerializationFormat::Cbor => 2,
+            SerializationFormat::Bincode => 3,
+            SerializationFormat::MessagePack => 4,
+        }
+    }
+    pub(crate) fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(SerializationFormat::Custom),
+            1 => Some(SerializationFormat::Candid),
+            2 => Some(SerializationFormat::Cbor),
+            3 => Some(SerializationFormat::Bincode),
+            4 => Some(SerializationFormat::MessagePack),
+            _ => None,
+        }
+    }
+}
+
+
+/// A migration step that was applied to an upgrade payload. Recorded in the stable-memory header.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MigrationRecord {
+    pub from_version: u32,
+    pub to_version: u32,
+    pub timestamp_nanos: u64,
+}
+
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct PayloadHeader {
+    pub format_id: u8,
+    pub compression_id: u8,
+    pub payload_offset: u64,
+    pub payload_length: u64,
+    pub checksum: [u8; 32],
+    pub type_fingerprint: u64,
+    pub timestamp_nanos: u64,
+    pub data_version: u32,
+    pub sequence: u64,
+    pub migration_log: Vec<MigrationRecord>,
+}
+
+impl PayloadHeader {
+    /// Checks the payload length and sha256 against the header.
+    pub(crate) fn verify_payload(&self, payload_length: u64, payload_checksum: [u8; 32], memory_id: MemoryId) -> Result<(), UpgradeError> {
+        if payload_length != self.payload_length {
+            return Err(UpgradeError::LengthMismatch{ memory_id, header_length: self.payload_length, stored_length: payload_length });
+        }
+        if payload_checksum != self.checksum {
+            return Err(UpgradeError::ChecksumMismatch{ memory_id });
+        }
+        Ok(())
+    }
+
+    fn encode(&self, d: &mut [u8], l: &mut [u8]) {
+        d[0] = self.format_id;
+        d[1] = self.compression_id;
+        d[8..16].copy_from_slice(&self.payload_length.to_be_bytes());
+        d[16..48].copy_from_slice(&self.checksum);
+        d[48..56].copy_from_slice(&self.type_fingerprint.to_be_bytes());
+        d[56..64].copy_from_slice(&self.timestamp_nanos.to_be_bytes());
+        d[64..68].copy_from_slice(&self.data_version.to_be_bytes());
+        d[72..80].copy_from_slice(&self.payload_offset.to_be_bytes());
+        d[80..88].copy_from_slice(&self.sequence.to_be_bytes());
+        
+        let migration_log = &self.migration_log[self.migration_log.len().saturating_sub(MIGRATION_LOG_CAPACITY)..];
+        l[0..2].copy_from_slice(&(migration_log.len() as u16).to_be_bytes());
+        for (i, record) in migration_log.iter().enumerate() {
+            let r = &mut l[MIGRATION_LOG_RECORDS_START + i * MIGRATION_RECORD_SIZE..MIGRATION_LOG_RECORDS_START + (i + 1) * MIGRATION_RECORD_SIZE];
+            r[0..4].copy_from_slice(&record.from_version.to_be_bytes());
+            r[4..8].copy_from_slice(&record.to_version.to_be_bytes());
+            r[8..16].copy_from_slice(&record.timestamp_nanos.to_be_bytes());
+        }
+    }
+
+    fn decode(d: &[u8], l: &[u8]) -> Self {
+        let migration_log_len = std::cmp::min(
+            u16::from_be_bytes(l[0..2].try_into().unwrap()) as usize,
+            MIGRATION_LOG_CAPACITY
+        );
+        Self {
+            format_id: d[0],
+            compression_id: d[1],
+            payload_offset: u64::from_be_bytes(d[72..80].try_into().unwrap()),
+            payload_length: u64::from_be_bytes(d[8..16].try_into().unwrap()),
+            checksum: d[16..48].try_into().unwrap(),
+            type_fingerprint: u64::from_be_bytes(d[48..56].try_into().unwrap()),
+            timestamp_nanos: u64::from_be_bytes(d[56..64].try_into().unwrap()),
+            data_version: u32::from_be_bytes(d[64..68].try_into().unwrap()),
+            sequence: u64::from_be_bytes(d[80..88].try_into().unwrap()),
+            migration_log: (0..migration_log_len).map(|i| {
+                let r = &l[MIGRATION_LOG_RECORDS_START + i * MIGRATION_RECORD_SIZE..MIGRATION_LOG_RECORDS_START + (i + 1) * MIGRATION_RECORD_SIZE];
+                MigrationRecord {
+                    from_version: u32::from_be_bytes(r[0..4].try_into().unwrap()),
+                    to_version: u32::from_be_bytes(r[4..8].try_into().unwrap()),
+                    timestamp_nanos: u64::from_be_bytes(r[8..16].try_into().unwrap()),
+                }
+            }).collect(),
+        }
+    }
+}
+
+
+/// Writes the header with the new payload in the slot `slot`, and the other slot as is.
+pub(crate) fn write_header(serialization_memory: &VirtualMemory<DefaultMemoryImpl>, slot: usize, new_payload: &PayloadHeader, other_slot: Option<&PayloadHeader>) {
+    let mut b = [0; STABLE_MEMORY_HEADER_SIZE_BYTES as usize];
+    b[0..8].copy_from_slice(&HEADER_MAGIC);
+    b[8..12].copy_from_slice(&HEADER_LAYOUT_VERSION.to_be_bytes());
+    b[12] = slot as u8;
+    encode_slot(&mut b, slot, new_payload);
+    if let Some(other) = other_slot {
+        encode_slot(&mut b, 1 - slot, other);
+    }
+    serialization_memory.write(0, &b);
+}
+
+/// Returns the slots that hold a payload, the newest first. 
+/// Returns `Ok(None)` when the header region is all zeros, which is the layout of the payloads
+/// written by the versions of this library before the header was introduced.
+pub(crate) fn read_header(serialization_memory: &VirtualMemory<DefaultMemoryImpl>, memory_id: MemoryId) -> Result<Option<Vec<(usize, PayloadHeader)>>, UpgradeError> {
+    if serialization_memory.size() == 0 {
+        return Err(UpgradeError::EmptyMemory{ memory_id });
+    }
+    let mut b = [0; STABLE_MEMORY_HEADER_SIZE_BYTES as usize];
+    serialization_memory.read(0, &mut b);
+
+    if b.iter().all(|byte| *byte == 0) {
+        return Ok(None);
+    }
+    if b[0..8] != HEADER_MAGIC {
+        return Err(UpgradeError::MissingHeader{ memory_id });
+    }
+    let layout_version: u32 = u32::from_be_bytes(b[8..12].try_into().unwrap());
+    if layout_version != HEADER_LAYOUT_VERSION {
+        return Err(UpgradeError::UnknownLayoutVersion{ memory_id, layout_version });
+    }
+    
+    let mut slots: Vec<(usize, PayloadHeader)> = (0..2)
+        .map(|slot| (slot, PayloadHeader::decode(&b[slot_start(slot)..slot_start(slot) + PAYLOAD_DESCRIPTOR_SIZE], &b[migration_log_start(slot)..migration_log_start(slot) + MIGRATION_LOG_SIZE])))
+        .filter(|(_, payload_header)| payload_header.sequence != 0)
+        .collect();
+    slots.sort_by_key(|(_, payload_header)| std::cmp::Reverse(payload_header.sequence));
+    
+    Ok(Some(slots))
+}
+
+fn encode_slot(b: &mut [u8], slot: usize, payload_header: &PayloadHeader) {
+    let (descriptor, migration_log) = b.split_at_mut(MIGRATION_LOG_START);
+    payload_header.encode(
+        &mut descriptor[slot_start(slot)..slot_start(slot) + PAYLOAD_DESCRIPTOR_SIZE],
+        &mut migration_log[slot * MIGRATION_LOG_SIZE..(slot + 1) * MIGRATION_LOG_SIZE]
+    );
+}
+
+fn slot_start(slot: usize) -> usize {
+    PAYLOAD_DESCRIPTOR_START + slot * PAYLOAD_DESCRIPTOR_SIZE
+}
+
+fn migration_log_start(slot: usize) -> usize {
+    MIGRATION_LOG_START + slot * MIGRATION_LOG_SIZE
+}
+
+
+pub(crate) fn sha256(b: &[u8]) -> [u8; 32] {
+    Sha256::digest(b).into()
+}
+
+/// A fingerprint of the rust type name. Only used as a hint in the error messages,
+/// the type name is not guaranteed to be stable between compiler versions.
+pub(crate) fn type_fingerprint<T>() -> u64 {
+    u64::from_be_bytes(sha256(std::any::type_name::<T>().as_bytes())[..8].try_into().unwrap())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_stable_structures::memory_manager::MemoryManager;
+
+    fn test_memory() -> VirtualMemory<DefaultMemoryImpl> {
+        let memory = MemoryManager::init(DefaultMemoryImpl::default()).get(MemoryId::new(0));
+        memory.grow(1);
+        memory
+    }
+
+    fn test_payload_header(sequence: u64) -> PayloadHeader {
+        PayloadHeader {
+            format_id: SerializationFormat::Candid.id(),
+            compression_id: 0,
+            payload_offset: STABLE_MEMORY_HEADER_SIZE_BYTES + sequence * 100,
+            payload_length: 50 + sequence,
+            checksum: sha256(&sequence.to_be_bytes()),
+            type_fingerprint: type_fingerprint::<u64>(),
+            timestamp_nanos: 1_700_000_000_000_000_000 + sequence,
+            data_version: 3,
+            sequence,
+            migration_log: vec![
+                MigrationRecord{ from_version: 1, to_version: 2, timestamp_nanos: 5 },
+                MigrationRecord{ from_version: 2, to_version: 3, timestamp_nanos: 6 },
+            ],
+        }
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let memory = test_memory();
+        let payload_header = test_payload_header(1);
+        write_header(&memory, 0, &payload_header, None);
+        let slots = read_header(&memory, MemoryId::new(0)).unwrap().unwrap();
+        assert_eq!(slots, vec![(0, payload_header)]);
+    }
+
+    #[test]
+    fn header_slots_newest_first() {
+        let memory = test_memory();
+        let older = test_payload_header(6);
+        let newer = test_payload_header(7);
+        write_header(&memory, 1, &newer, Some(&older));
+        let slots = read_header(&memory, MemoryId::new(0)).unwrap().unwrap();
+        assert_eq!(slots, vec![(1, newer.clone()), (0, older.clone())]);
+        // the next payload goes into the slot of the older payload.
+        let newest = test_payload_header(8);
+        write_header(&memory, 0, &newest, Some(&newer));
+        let slots = read_header(&memory, MemoryId::new(0)).unwrap().unwrap();
+        assert_eq!(slots, vec![(0, newest), (1, newer)]);
+    }
+
+    #[test]
+    fn header_migration_log_of_each_slot() {
+        let memory = test_memory();
+        let older = test_payload_header(1);
+        let mut newer = test_payload_header(2);
+        newer.migration_log.push(MigrationRecord{ from_version: 3, to_version: 4, timestamp_nanos: 7 });
+        write_header(&memory, 1, &newer, Some(&older));
+        let slots = read_header(&memory, MemoryId::new(0)).unwrap().unwrap();
+        assert_eq!(slots[0].1.migration_log, newer.migration_log);
+        assert_eq!(slots[1].1.migration_log, older.migration_log);
+    }
+
+    #[test]
+    fn header_migration_log_keeps_the_newest_records() {
+        let memory = test_memory();
+        let payload_header = PayloadHeader{
+            migration_log: (0..MIGRATION_LOG_CAPACITY as u32 + 5).map(|i| MigrationRecord{ from_version: i, to_version: i + 1, timestamp_nanos: i as u64 }).collect(),
+            ..test_payload_header(1)
+        };
+        write_header(&memory, 0, &payload_header, None);
+        let slots = read_header(&memory, MemoryId::new(0)).unwrap().unwrap();
+        assert_eq!(slots[0].1.migration_log, payload_header.migration_log[5..]);
+    }
+
+    #[test]
+    fn header_skips_empty_slot() {
+        let memory = test_memory();
+        write_header(&memory, 1, &test_payload_header(1), None);
+        let slots = read_header(&memory, MemoryId::new(0)).unwrap().unwrap();
+        assert_eq!(slots.iter().map(|(slot, _)| *slot).collect::<Vec<usize>>(), vec![1]);
+    }
+
+    #[test]
+    fn verify_payload_checks_the_length_and_the_sha256() {
+        let payload_header = PayloadHeader{ payload_length: 3, checksum: sha256(&[1, 2, 3]), ..test_payload_header(1) };
+        let memory_id = MemoryId::new(0);
+        assert_eq!(payload_header.verify_payload(3, sha256(&[1, 2, 3]), memory_id), Ok(()));
+        assert_eq!(
+            payload_header.verify_payload(4, sha256(&[1, 2, 3, 4]), memory_id),
+            Err(UpgradeError::LengthMismatch{ memory_id, header_length: 3, stored_length: 4 })
+        );
+        assert_eq!(payload_header.verify_payload(3, sha256(&[1, 2, 4]), memory_id), Err(UpgradeError::ChecksumMismatch{ memory_id }));
+    }
+
+    #[test]
+    fn header_without_magic() {
+        let memory = test_memory();
+        assert_eq!(read_header(&memory, MemoryId::new(0)).unwrap(), None);
+        memory.write(0, b"NOTMAGIC");
+        assert_eq!(read_header(&memory, MemoryId::new(0)).unwrap_err(), UpgradeError::MissingHeader{ memory_id: MemoryId::new(0) });
+    }
+
+    #[test]
+    fn header_unknown_layout_version() {
+        let memory = test_memory();
+        write_header(&memory, 0, &test_payload_header(1), None);
+        memory.write(8, &(HEADER_LAYOUT_VERSION + 1).to_be_bytes());
+        assert_eq!(
+            read_header(&memory, MemoryId::new(0)).unwrap_err(),
+            UpgradeError::UnknownLayoutVersion{ memory_id: MemoryId::new(0), layout_version: HEADER_LAYOUT_VERSION + 1 }
+        );
+    }
+}
@@ -0,0 +1,81 @@
+use std::cell::RefCell;
+use std::time::Duration;
+
+use ic_cdk::api::time;
+
+use candid::{CandidType, Deserialize};
+
+use crate::localkey::refcell::with;
+use crate::MemoryId;
+use crate::stable_memory_tools::{check_invariants, caller_is_controller_gaurd, reply_result};
+use crate::timer_schedules::{TimerSchedules, set_schedule_interval, update_schedule_state};
+
+
+struct InvariantCheckState {
+    last_check_timestamp_nanos: Option<u64>,
+    failed_checks: Vec<String>,
+}
+
+thread_local!{
+    static INVARIANT_CHECK_SCHEDULES: RefCell<TimerSchedules<InvariantCheckState>> = const { RefCell::new(TimerSchedules::new()) };
+}
+
+
+/// Runs the invariant checks of the memory-id, see [add_invariant](crate::add_invariant), on the global variable every `interval`.
+/// `None` stops the checks of the memory-id.
+/// The failed checks are printed to the canister log and reported by the `controller_invariant_check_schedules` canister method.
+///
+/// The timers do not survive an upgrade, call this function after the invariants are added, 
+/// in the canister_init method and in the post_upgrade hook.
+///
+/// Needs the `timers` cargo feature.
+pub fn set_invariant_check_interval(memory_id: MemoryId, opt_interval: Option<Duration>) {
+    set_schedule_interval(&INVARIANT_CHECK_SCHEDULES, memory_id, opt_interval, check, |_| {
+        InvariantCheckState {
+            last_check_timestamp_nanos: None,
+            failed_checks: Vec::new(),
+        }
+    });
+}
+
+fn check(memory_id: MemoryId) {
+    let failed_checks: Vec<String> = check_invariants(memory_id).unwrap_or_else(|e| vec![e]);
+    if !failed_checks.is_empty() {
+        ic_cdk::println!("memory-id: {:?}: the global variable does not pass the invariant checks: {}", memory_id, failed_checks.join("; "));
+    }
+    update_schedule_state(&INVARIANT_CHECK_SCHEDULES, memory_id, |schedule| {
+        schedule.last_check_timestamp_nanos = Some(time());
+        schedule.failed_checks = failed_checks;
+    });
+}
+
+
+/// The invariant check schedule of a memory-id, returned by the `controller_invariant_check_schedules` canister method.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct InvariantCheckStatus {
+    pub memory_id: u8,
+    pub interval_seconds: u64,
+    /// `None` when the timer did not run since the interval was set or since the last upgrade.
+    pub last_check_timestamp_nanos: Option<u64>,
+    /// The checks that failed in the last run.
+    pub failed_checks: Vec<String>,
+}
+
+
+#[export_name = "canister_query controller_invariant_check_schedules"]
+extern "C" fn controller_invariant_check_schedules() {
+    caller_is_controller_gaurd();
+    
+    with(&INVARIANT_CHECK_SCHEDULES, |schedules| {
+        reply_result::<Vec<InvariantCheckStatus>>(Ok(
+            schedules.values().map(|schedule| {
+                InvariantCheckStatus {
+                    memory_id: schedule.memory_id,
+                    interval_seconds: schedule.interval.as_secs(),
+                    last_check_timestamp_nanos: schedule.state.last_check_timestamp_nanos,
+                    failed_checks: schedule.state.failed_checks.clone(),
+                }
+            }).collect(),
+        ));
+    });
+}
@@ -10,6 +10,8 @@
 //!
 //! For a global data type to be serializable, it must implement the [Serializable] trait.
 //! Types that implement candid's `CandidType` and `Deserialize` traits auto-implement the [Serializable] trait with the [candid](https://docs.rs/candid/latest/candid/index.html) binary serialization format. 
+//! For the CBOR, bincode, and MessagePack formats, enable the `cbor`, `bincode`, or `msgpack` cargo feature and wrap the type in a wrapper from the [formats] module.
+//! For a custom serialization of a type that also implements `CandidType`, register it with an [UpgradeCodec].
 //!
 //! 
 //! For the safety and to make sure that your data is always accessible even if something goes wrong in the 
@@ -22,37 +24,244 @@
 //! type MemoryId = nat8;
 //! type Offset = nat64;
 //! type Length = nat64;
+//! type MaxLength = nat64;
+//! type ChunkIndex = nat32;
+//! type ChunkCount = nat32;
+//! type UploadSessionId = nat64;
 //! type StateSnapshotLength = nat64;
+//! type Sha256 = blob;
 //! type WasmPages = nat64;
+//! type SerializationFormat = variant { Custom; Candid; Cbor; Bincode; MessagePack };
+//! type Compression = variant { None; Deflate; Lz4; Zstd };
+//! type StateSnapshotSource = variant { Serialization; Upload };
+//! type StateSnapshotInfo = record {
+//!     memory_id : MemoryId;
+//!     type_name : text;
+//!     snapshot_length : nat64;
+//!     snapshot_sha256 : blob;
+//!     snapshot_compression : Compression;
+//!     snapshot_timestamp_nanos : opt nat64;
+//!     snapshot_source : opt StateSnapshotSource;
+//! };
+//! type StateGeneration = record {
+//!     generation : nat64;
+//!     last_modified_timestamp_nanos : opt nat64;
+//! };
+//! type MemoryIdUpgradeReadiness = record {
+//!     memory_id : MemoryId;
+//!     payload_length : nat64;
+//!     instructions : nat64;
+//!     stable_memory_growth_wasm_pages : nat64;
+//!     modified : bool;
+//!     error : opt text;
+//! };
+//! type UpgradeReadiness = record {
+//!     memory_ids : vec MemoryIdUpgradeReadiness;
+//!     total_instructions : nat64;
+//!     upgrade_instruction_limit : nat64;
+//!     total_stable_memory_growth_wasm_pages : nat64;
+//!     ready : bool;
+//! };
+//! type UpgradePayloadVerification = record {
+//!     payload_length : nat64;
+//!     timestamp_nanos : opt nat64;
+//!     error : opt text;
+//! };
+//! type StateSnapshotValidation = record {
+//!     snapshot_length : nat64;
+//!     decoded_length : nat64;
+//!     error : opt text;
+//!     failed_checks : vec text;
+//! };
+//! type InvariantCheckStatus = record {
+//!     memory_id : MemoryId;
+//!     interval_seconds : nat64;
+//!     last_check_timestamp_nanos : opt nat64;
+//!     failed_checks : vec text;
+//! };
+//! type Label = text;
+//! type NamedStateSnapshot = record {
+//!     label : Label;
+//!     timestamp_nanos : nat64;
+//!     length : nat64;
+//!     sha256 : blob;
+//!     compression : Compression;
+//! };
+//! type CheckpointStatus = record {
+//!     memory_id : MemoryId;
+//!     interval_seconds : nat64;
+//!     last_checkpoint_timestamp_nanos : opt nat64;
+//!     last_checkpoint_error : opt text;
+//! };
+//! type DownloadChunk = record {
+//!     bytes : blob;
+//!     total_length : nat64;
+//!     next_offset : Offset;
+//! };
+//! type StateSnapshotUploadStatus = record {
+//!     chunk_count : ChunkCount;
+//!     received_chunks : nat32;
+//!     missing_chunks : vec ChunkIndex;
+//! };
+//! type ByteRange = record {
+//!     offset : Offset;
+//!     length : Length;
+//! };
+//! type UploadSessionStatus = record {
+//!     session_id : UploadSessionId;
+//!     memory_id : MemoryId;
+//!     total_length : nat64;
+//!     received_length : nat64;
+//!     missing_ranges : vec ByteRange;
+//!     expires_at_nanos : nat64;
+//! };
+//! type ControllerError = variant {
+//!     UnknownMemoryId : record { memory_id : MemoryId };
+//!     OutOfRange : record { offset : Offset; length : Length; size : nat64 };
+//!     ReplyTooLarge : record { length : Length; max_length : nat64 };
+//!     CompressionNotEnabled : record { compression : Compression };
+//!     Serialization : text;
+//!     Compression : text;
+//!     Decode : text;
+//!     InvariantChecks : vec text;
+//!     NoRollbackPoint;
+//!     NamedSnapshotNotFound : record { label : Label };
+//!     LabelTooLong : record { max_length : nat64 };
+//!     ChunkIndexOutOfRange : record { chunk_index : ChunkIndex; chunk_count : ChunkCount };
+//!     ChunkCountMismatch : record { chunk_count : ChunkCount };
+//!     ChunkMismatch : record { chunk_index : ChunkIndex };
+//!     IncompleteUpload : record { received_chunks : nat32; chunk_count : ChunkCount };
+//!     UploadSessionInProgress : record { session_id : UploadSessionId; expires_at_nanos : nat64 };
+//!     UnknownUploadSession : record { session_id : UploadSessionId };
+//!     IncompleteUploadSession : record { received_length : nat64; total_length : nat64 };
+//!     UploadChecksumMismatch : record { session_id : UploadSessionId };
+//!     InvalidSha256 : record { length : nat64 };
+//!     StableMemoryGrowFailed : record { pages : WasmPages };
+//! };
 //! 
 //! service : {
+//!     // Each method returns the Err variant with a ControllerError instead of trapping, the method does not change the state on an Err.
+//!     // An expired upload session is dropped before any method that changes a snapshot, also when that method returns an Err.
+//!     
 //!     // Takes a snapshot of the data structure registered at the given MemoryId.
-//!     controller_create_state_snapshot : (MemoryId) -> (StateSnapshotLength);
+//!     // The snapshot is compressed with the compression set for the MemoryId.
+//!     // Returns the length and the sha256 of the snapshot bytes.
+//!     controller_create_state_snapshot : (MemoryId) -> (variant { Ok : record { StateSnapshotLength; Sha256 }; Err : ControllerError });
+//!     
+//!     // Takes an uncompressed snapshot of the data structure registered at the given MemoryId.
+//!     controller_create_raw_state_snapshot : (MemoryId) -> (variant { Ok : record { StateSnapshotLength; Sha256 }; Err : ControllerError });
+//!     
+//!     // The sha256 of the current snapshot bytes of the given MemoryId.
+//!     // Check it after an upload with controller_append_state_snapshot, before controller_load_state_snapshot.
+//!     controller_state_snapshot_sha256 : (MemoryId) -> (variant { Ok : Sha256; Err : ControllerError }) query;
+//!     
+//!     // The compression of the current snapshot bytes of the given MemoryId.
+//!     controller_state_snapshot_compression : (MemoryId) -> (variant { Ok : Compression; Err : ControllerError }) query;
+//!     
+//!     // The serialization format of the data structure registered at the given MemoryId.
+//!     controller_state_snapshot_format : (MemoryId) -> (variant { Ok : SerializationFormat; Err : ControllerError }) query;
+//!     
+//!     // The registered MemoryIds with the type name of the data structure and the current snapshot of each MemoryId.
+//!     controller_list_state_snapshots : () -> (variant { Ok : vec StateSnapshotInfo; Err : ControllerError }) query;
+//!     
+//!     // The number of modifications of the data structure registered at the given MemoryId
+//!     // since it was registered in this canister version, and the time of the last modification.
+//!     controller_state_generation : (MemoryId) -> (variant { Ok : StateGeneration; Err : ControllerError }) query;
 //!     
 //!     // Download the snapshot of the data corresponding to the given MemoryId.
-//!     // Download the data in chunks.
-//!     controller_download_state_snapshot : (MemoryId, Offset, Length) -> (blob) query;
+//!     // Download the data in chunks of at most canister_tools::MAX_REPLY_BLOB_LENGTH bytes.
+//!     controller_download_state_snapshot : (MemoryId, Offset, Length) -> (variant { Ok : blob; Err : ControllerError }) query;
+//!     
+//!     // Download the snapshot in chunks of at most MaxLength bytes, clamped to the bytes that fit in one reply.
+//!     // Call it with the next_offset of the last chunk until the next_offset is equal to the total_length.
+//!     controller_download_state_snapshot_chunk : (MemoryId, Offset, MaxLength) -> (variant { Ok : DownloadChunk; Err : ControllerError }) query;
 //!     
 //!     // Clears the snapshot of the data corresponding to the given MemoryId.
 //!     // When uploading data onto the data structure, call this method first to clear
 //!     // the snapshot before uploading a customized snapshot.
-//!     controller_clear_state_snapshot : (MemoryId) -> ();
+//!     controller_clear_state_snapshot : (MemoryId) -> (variant { Ok; Err : ControllerError });
+//!     
+//!     // Same as controller_clear_state_snapshot for the upload of a snapshot compressed with the given Compression.
+//!     controller_clear_state_snapshot_with_compression : (MemoryId, Compression) -> (variant { Ok; Err : ControllerError });
 //!     
 //!     // Upload the serialized data structure for the given MemoryId in chunks that can then be deserialized and loaded onto the canister global variable.   
-//!     controller_append_state_snapshot : (MemoryId, blob) -> ();
+//!     controller_append_state_snapshot : (MemoryId, blob) -> (variant { Ok; Err : ControllerError });
+//!     
+//!     // Upload the snapshot in ChunkCount chunks, each chunk at its ChunkIndex. The chunks can arrive in any order and in parallel,
+//!     // a chunk that is put again replaces itself. The snapshot can be loaded once all of the chunks arrive.
+//!     // The first chunk clears the snapshot and keeps the compression of the last clear. Clear the snapshot to start a new upload.
+//!     controller_put_state_snapshot_chunk : (MemoryId, ChunkIndex, ChunkCount, blob) -> (variant { Ok; Err : ControllerError });
+//!     
+//!     // The chunks of the upload with controller_put_state_snapshot_chunk that arrived and that are missing.
+//!     controller_state_snapshot_upload_status : (MemoryId) -> (variant { Ok : opt StateSnapshotUploadStatus; Err : ControllerError }) query;
+//!     
+//!     // Begins an upload session of a snapshot with the total length, the sha256, and the compression of the snapshot.
+//!     // The session holds the snapshot buffer of the MemoryId, the other methods that change the snapshot return an error until the session ends.
+//!     // The session ends when controller_load_state_snapshot loads the snapshot, when it is canceled, or when it expires.
+//!     // A session expires an hour after its last chunk, set the timeout with canister_tools::set_upload_session_timeout.
+//!     // The session-ids are not reused after an upgrade, the session-id of a session that does not continue after an upgrade returns UnknownUploadSession.
+//!     // With canister_tools::set_upload_staging, the chunks are written into the stable memory and controller_load_state_snapshot decodes the snapshot through a reader.
+//!     controller_begin_state_snapshot_upload : (MemoryId, Length, Sha256, Compression) -> (variant { Ok : UploadSessionId; Err : ControllerError });
 //!     
-//!     // Deserializes the snapshot for the data structure corresponding to the given MemoryId
+//!     // Writes the chunk at the offset of the upload. The chunks can arrive in any order and in parallel, a chunk that is written twice is harmless.
+//!     // controller_load_state_snapshot loads the snapshot once all of the bytes arrive and their sha256 matches the declared sha256.
+//!     controller_upload_state_snapshot_chunk : (UploadSessionId, Offset, blob) -> (variant { Ok; Err : ControllerError });
+//!     controller_state_snapshot_upload_session : (UploadSessionId) -> (variant { Ok : UploadSessionStatus; Err : ControllerError }) query;
+//!     controller_cancel_state_snapshot_upload : (UploadSessionId) -> (variant { Ok; Err : ControllerError });
+//!     
+//!     // Decompresses and deserializes the snapshot for the data structure corresponding to the given MemoryId
+//!     // without loading it onto the canister's global variable, and reports the error and the failed checks.
+//!     // Call it before controller_load_state_snapshot. It does not change the state.
+//!     controller_validate_state_snapshot : (MemoryId) -> (variant { Ok : StateSnapshotValidation; Err : ControllerError });
+//!     
+//!     // Decompresses and deserializes the snapshot for the data structure corresponding to the given MemoryId
 //!     // and loads it onto the canister's global variable.
-//!     controller_load_state_snapshot : (MemoryId) -> ();
+//!     controller_load_state_snapshot : (MemoryId) -> (variant { Ok; Err : ControllerError });
+//!     
+//!     // Loads the newest rollback point of the given MemoryId onto the canister's global variable.
+//!     // controller_load_state_snapshot stores a rollback point of the current state before it loads a snapshot.
+//!     controller_rollback_state_snapshot : (MemoryId) -> (variant { Ok; Err : ControllerError });
+//!     
+//!     // Runs the invariant checks of the data structure registered at the given MemoryId, returns the failed checks.
+//!     controller_check_invariants : (MemoryId) -> (variant { Ok : vec text; Err : ControllerError }) query;
+//!     
+//!     // Named snapshots are stored in the stable memory and persist through the upgrades.
+//!     // Serializes the data structure registered at the given MemoryId into a named snapshot,
+//!     // replaces the named snapshot with the same label.
+//!     controller_create_named_state_snapshot : (MemoryId, Label) -> (variant { Ok : NamedStateSnapshot; Err : ControllerError });
+//!     controller_list_named_state_snapshots : (MemoryId) -> (variant { Ok : vec NamedStateSnapshot; Err : ControllerError }) query;
+//!     controller_download_named_state_snapshot : (MemoryId, Label, Offset, Length) -> (variant { Ok : blob; Err : ControllerError }) query;
+//!     controller_download_named_state_snapshot_chunk : (MemoryId, Label, Offset, MaxLength) -> (variant { Ok : DownloadChunk; Err : ControllerError }) query;
+//!     controller_delete_named_state_snapshot : (MemoryId, Label) -> (variant { Ok; Err : ControllerError });
+//!     // Loads the named snapshot onto the canister's global variable, the same way as controller_load_state_snapshot.
+//!     controller_load_named_state_snapshot : (MemoryId, Label) -> (variant { Ok; Err : ControllerError });
+//! 
+//!     // Serializes each registered data structure the same way as the pre_upgrade without writing it,
+//!     // and reports the payload lengths, the instructions, and the stable memory growth.
+//!     // Call it before an upgrade to check that the pre_upgrade fits in the upgrade instruction limit.
+//!     controller_check_upgrade_readiness : () -> (variant { Ok : UpgradeReadiness; Err : ControllerError });
+//!     
+//!     // Decodes the stored payload that the next upgrade loads, as the data structure registered at the given MemoryId,
+//!     // without loading it onto the canister's global variable. The error is null when the payload decodes.
+//!     controller_verify_upgrade_payload : (MemoryId) -> (variant { Ok : UpgradePayloadVerification; Err : ControllerError }) query;
 //! 
 //!
 //!
 //!     // Common stable memory functions as canister methods.
 //!     // Useful when using a custom stable-memory strategy for one or some of the MemoryIds. 
-//!     controller_stable_memory_read : (MemoryId, Offset, Length) -> (blob) query;
-//!     controller_stable_memory_write : (MemoryId, Offset, blob) -> ();
-//!     controller_stable_memory_size : (MemoryId) -> (nat64) query;
-//!     controller_stable_memory_grow : (MemoryId, WasmPages) -> (int64);
+//!     // The read length is at most canister_tools::MAX_REPLY_BLOB_LENGTH bytes, the grow returns the previous size.
+//!     controller_stable_memory_read : (MemoryId, Offset, Length) -> (variant { Ok : blob; Err : ControllerError }) query;
+//!     controller_stable_memory_write : (MemoryId, Offset, blob) -> (variant { Ok; Err : ControllerError });
+//!     controller_stable_memory_size : (MemoryId) -> (variant { Ok : nat64; Err : ControllerError }) query;
+//!     controller_stable_memory_grow : (MemoryId, WasmPages) -> (variant { Ok : WasmPages; Err : ControllerError });
+//! 
+//!     // The checkpoint schedule of each MemoryId and the time of its last checkpoint.
+//!     // Only with the checkpoints cargo feature.
+//!     controller_checkpoints : () -> (variant { Ok : vec CheckpointStatus; Err : ControllerError }) query;
+//!     
+//!     // The invariant check schedule of each MemoryId and the checks that failed in the last run.
+//!     // Only with the timers cargo feature.
+//!     controller_invariant_check_schedules : () -> (variant { Ok : vec InvariantCheckStatus; Err : ControllerError }) query;
 //! }
 //! ```
 //! 
@@ -140,16 +349,75 @@
 mod stable_memory_tools;
 pub use stable_memory_tools::*;
 
+mod header;
+pub use header::{SerializationFormat, MigrationRecord, HEADER_LAYOUT_VERSION, MIGRATION_LOG_CAPACITY};
+
+mod error;
+pub use error::{UpgradeError, ControllerError};
+
+mod migrations;
+pub use migrations::Migrations;
+
+mod stable_io;
+pub use stable_io::{StableReader, StableWriter};
+
+mod compression;
+pub use compression::Compression;
+
+mod rollback;
+
+mod chunked_upload;
+
+mod upload_sessions;
+pub use upload_sessions::{set_upload_session_timeout, ByteRange};
+
+mod upload_staging;
+
+mod named_snapshots;
+pub use named_snapshots::NAMED_SNAPSHOT_LABEL_MAX_LENGTH;
+
+#[cfg(feature = "timers")]
+mod timer_schedules;
+
+#[cfg(feature = "checkpoints")]
+mod checkpoints;
+#[cfg(feature = "checkpoints")]
+pub use checkpoints::{set_checkpoint_interval, CheckpointStatus};
+
+#[cfg(feature = "timers")]
+mod invariant_checks;
+#[cfg(feature = "timers")]
+pub use invariant_checks::{set_invariant_check_interval, InvariantCheckStatus};
+
+pub mod formats;
+#[cfg(feature = "cbor")]
+pub use formats::{Cbor, CborCodec};
+#[cfg(feature = "bincode")]
+pub use formats::{Bincode, BincodeCodec};
+#[cfg(feature = "msgpack")]
+pub use formats::{MessagePack, MessagePackCodec};
+
+// The system api is only available in a canister, the unit tests run at a fixed time.
+#[cfg(not(test))]
+pub(crate) use ic_cdk::api::{time, instruction_counter};
+#[cfg(test)]
+pub(crate) fn time() -> u64 { 0 }
+#[cfg(test)]
+pub(crate) fn instruction_counter() -> u64 { 0 }
+
 pub mod localkey {
     pub mod refcell {
         use std::{
             cell::RefCell,
             thread::LocalKey,
+            collections::BTreeMap,
         };
         /// Function for a RefCell defined in a thread_local!{}, gives direct immutable access to the data structure within the RefCell.
         /// 
         /// ## Sample
         /// ```
+        /// # use std::cell::RefCell;
+        /// # use canister_tools::localkey::refcell::{with, with_mut};
         /// struct House {
         ///     color: String,
         ///     size: u32
@@ -182,6 +450,8 @@ pub mod localkey {
         /// 
         /// ## Sample
         /// ```
+        /// # use std::cell::RefCell;
+        /// # use canister_tools::localkey::refcell::{with, with_mut};
         /// struct House {
         ///     color: String,
         ///     size: u32
@@ -202,12 +472,50 @@ pub mod localkey {
         ///     });
         /// }
         /// ```        
+        ///
+        /// When the RefCell is registered with the canister-tools library, each call counts as a modification of the data, 
+        /// see [set_dirty_tracking](crate::set_dirty_tracking).
         pub fn with_mut<T: 'static, R, F>(s: &'static LocalKey<RefCell<T>>, f: F) -> R
         where 
             F: FnOnce(&mut T) -> R 
         {
             s.with(|b| {
-                f(&mut *b.borrow_mut())
+                let mut data = b.borrow_mut();
+                mark_modified(s as *const LocalKey<RefCell<T>> as usize);
+                f(&mut *data)
+            })
+        }
+        
+        
+        #[derive(Clone, Copy)]
+        pub(crate) struct Generation {
+            pub generation: u64,
+            pub last_modified_timestamp_nanos: Option<u64>,
+        }
+        
+        thread_local!{
+            // the generations of the registered RefCells, by the address of their LocalKey.
+            static GENERATIONS: RefCell<BTreeMap<usize, Generation>> = const { RefCell::new(BTreeMap::new()) };
+        }
+        
+        fn mark_modified(localkey_address: usize) {
+            GENERATIONS.with(|generations| {
+                if let Some(g) = generations.borrow_mut().get_mut(&localkey_address) {
+                    g.generation += 1;
+                    g.last_modified_timestamp_nanos = Some(crate::time());
+                }
+            });
+        }
+        
+        pub(crate) fn track_generation(localkey_address: usize) {
+            GENERATIONS.with(|generations| {
+                generations.borrow_mut().entry(localkey_address).or_insert(Generation{ generation: 0, last_modified_timestamp_nanos: None });
+            });
+        }
+        
+        pub(crate) fn generation(localkey_address: usize) -> Generation {
+            GENERATIONS.with(|generations| {
+                generations.borrow().get(&localkey_address).copied().unwrap_or(Generation{ generation: 0, last_modified_timestamp_nanos: None })
             })
         }
     }
@@ -220,6 +528,8 @@ pub mod localkey {
         ///
         /// ## Sample
         /// ```
+        /// # use std::cell::Cell;
+        /// # use canister_tools::localkey::cell::{get, set};
         /// thread_local!{
         ///     static VALUE: Cell<u64> = Cell::new(5);
         /// } 
@@ -235,6 +545,8 @@ pub mod localkey {
         /// 
         /// ## Sample
         /// ```
+        /// # use std::cell::Cell;
+        /// # use canister_tools::localkey::cell::{get, set};
         /// thread_local!{
         ///     static VALUE: Cell<u64> = Cell::new(5);
         /// } 
@@ -0,0 +1,183 @@
+use std::rc::Rc;
+use std::io::Read;
+
+use crate::{Serializable, SerializationFormat, UpgradeCodec, SerializableCodec};
+use crate::header::type_fingerprint;
+use crate::stable_memory_tools::{RegisterFn, register};
+
+
+type DecodeAndMigrateFn<Data> = Box<dyn Fn(&mut dyn Read) -> Result<Data, String>>;
+
+pub(crate) struct MigrationStep<Data> {
+    pub format: SerializationFormat,
+    pub type_fingerprint: u64,
+    pub decode_and_migrate: DecodeAndMigrateFn<Data>,
+}
+
+impl<Data: 'static> MigrationStep<Data> {
+    fn direct<C: UpgradeCodec<Data>>() -> Self {
+        Self {
+            format: C::format(),
+            type_fingerprint: type_fingerprint::<Data>(),
+            decode_and_migrate: Box::new(|r| C::backward_from(r)),
+        }
+    }
+}
+
+
+/// A chain of typed migration steps, one for each data version of a global variable.
+///
+/// The data version of the payload is recorded in the stable-memory header.
+/// [post_upgrade_with_migrations](crate::post_upgrade_with_migrations) deserializes the payload as the type of the stored version,
+/// then applies the remaining steps in order up to the latest version.
+/// Each applied step is recorded in the header.
+///
+/// The global variables registered with [init](crate::init) and [post_upgrade](crate::post_upgrade) are at data version 0.
+///
+/// Each version is serialized with [SerializableCodec], use the `_with_codec` methods for another [UpgradeCodec](crate::UpgradeCodec).
+///
+/// ## Sample
+/// ```ignore
+/// fn migrations() -> Migrations<DataV2> {
+///     Migrations::<DataV0>::new()
+///         .then(|v0: DataV0| DataV1::from(v0))
+///         .then(|v1: DataV1| DataV2::from(v1))
+/// }
+///
+/// #[init]
+/// fn init() {
+///     canister_tools::init_with_migrations(&DATA, DATA_UPGRADE_MEMORY_ID, &migrations());
+/// }
+///
+/// #[post_upgrade]
+/// fn post_upgrade() {
+///     canister_tools::post_upgrade_with_migrations(&DATA, DATA_UPGRADE_MEMORY_ID, migrations());
+/// }
+/// ```
+pub struct Migrations<Data: 'static> {
+    first_version: u32,
+    steps: Vec<MigrationStep<Data>>,
+    register_fn: RegisterFn<Data>,
+}
+
+impl<Data: 'static + Serializable> Migrations<Data> {
+    /// Starts the chain with `Data` as data version 0.
+    pub fn new() -> Self {
+        Self::starting_at(0)
+    }
+    /// Starts the chain with `Data` as the given data version.
+    /// Use this when the payloads of the older versions are not in use anymore.
+    pub fn starting_at(first_version: u32) -> Self {
+        Self::starting_at_with_codec::<SerializableCodec>(first_version)
+    }
+    /// Adds a step that migrates the latest version into the next version.
+    pub fn then<Next, F>(self, migrate: F) -> Migrations<Next>
+        where
+            Next: 'static + Serializable,
+            F: 'static + Fn(Data) -> Next
+        {
+        self.then_with_codec::<Next, SerializableCodec, F>(migrate)
+    }
+}
+
+impl<Data: 'static> Migrations<Data> {
+    /// Same as [new](Migrations::new) with the given codec for `Data`.
+    pub fn new_with_codec<C: 'static + UpgradeCodec<Data>>() -> Self {
+        Self::starting_at_with_codec::<C>(0)
+    }
+    /// Same as [starting_at](Migrations::starting_at) with the given codec for `Data`.
+    pub fn starting_at_with_codec<C: 'static + UpgradeCodec<Data>>(first_version: u32) -> Self {
+        Self {
+            first_version,
+            steps: vec![MigrationStep::direct::<C>()],
+            register_fn: register::<Data, C>,
+        }
+    }
+    /// Same as [then](Migrations::then) with the given codec for `Next`.
+    pub fn then_with_codec<Next, C, F>(self, migrate: F) -> Migrations<Next>
+        where
+            Next: 'static,
+            C: 'static + UpgradeCodec<Next>,
+            F: 'static + Fn(Data) -> Next
+        {
+        let migrate = Rc::new(migrate);
+        let mut steps: Vec<MigrationStep<Next>> = self.steps.into_iter().map(|step| {
+            let migrate = migrate.clone();
+            MigrationStep{
+                format: step.format,
+                type_fingerprint: step.type_fingerprint,
+                decode_and_migrate: Box::new(move |r| {
+                    Ok(migrate((step.decode_and_migrate)(r)?))
+                }),
+            }
+        }).collect();
+        steps.push(MigrationStep::direct::<C>());
+        Migrations {
+            first_version: self.first_version,
+            steps,
+            register_fn: register::<Next, C>,
+        }
+    }
+    /// The data version of `Data`.
+    pub fn latest_version(&self) -> u32 {
+        self.first_version + self.steps.len() as u32 - 1
+    }
+    pub(crate) fn register_fn(&self) -> RegisterFn<Data> {
+        self.register_fn
+    }
+    pub(crate) fn step(&self, data_version: u32) -> Option<&MigrationStep<Data>> {
+        self.steps.get(data_version.checked_sub(self.first_version)? as usize)
+    }
+}
+
+impl<Data: 'static + Serializable> Default for Migrations<Data> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn migrations() -> Migrations<String> {
+        Migrations::<u32>::new()
+            .then(|v0: u32| v0 as u64 * 2)
+            .then(|v1: u64| v1.to_string())
+    }
+
+    fn decode_and_migrate<Data>(migrations: &Migrations<Data>, data_version: u32, b: &[u8]) -> Result<Data, String> {
+        (migrations.step(data_version).unwrap().decode_and_migrate)(&mut &b[..])
+    }
+
+    #[test]
+    fn latest_version_and_steps() {
+        let migrations = migrations();
+        assert_eq!(migrations.latest_version(), 2);
+        assert!(migrations.step(2).is_some());
+        assert!(migrations.step(3).is_none());
+        assert_eq!(migrations.step(0).unwrap().format, SerializationFormat::Candid);
+        assert_eq!(migrations.step(0).unwrap().type_fingerprint, type_fingerprint::<u32>());
+        assert_eq!(migrations.step(2).unwrap().type_fingerprint, type_fingerprint::<String>());
+    }
+
+    #[test]
+    fn steps_starting_at_a_data_version() {
+        let migrations = Migrations::<u32>::starting_at(3).then(|v3: u32| v3 as u64);
+        assert_eq!(migrations.latest_version(), 4);
+        assert!(migrations.step(2).is_none());
+        assert!(migrations.step(3).is_some());
+        assert!(migrations.step(4).is_some());
+        assert!(migrations.step(5).is_none());
+    }
+
+    #[test]
+    fn each_step_applies_the_remaining_migrations() {
+        let migrations = migrations();
+        assert_eq!(decode_and_migrate(&migrations, 0, &candid::encode_one(5u32).unwrap()), Ok("10".to_string()));
+        assert_eq!(decode_and_migrate(&migrations, 1, &candid::encode_one(5u64).unwrap()), Ok("5".to_string()));
+        assert_eq!(decode_and_migrate(&migrations, 2, &candid::encode_one("5").unwrap()), Ok("5".to_string()));
+        assert!(decode_and_migrate(&migrations, 0, &[1, 2, 3]).is_err());
+    }
+}
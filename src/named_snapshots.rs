@@ -0,0 +1,253 @@
+// The named state-snapshots, stored in a StableBTreeMap in the reserved NAMED_SNAPSHOTS_MEMORY_ID 
+// so that they persist through the upgrades. 
+//
+// Each named snapshot is stored as an info entry (part 0) and the snapshot bytes in chunks (part 1..), 
+// keyed by the memory-id of the global variable, the label, and the part, 
+// so that a listing or a download reads only the entries it needs.
+
+use std::cell::RefCell;
+use std::borrow::Cow;
+use std::ops::Bound::{Included, Excluded, Unbounded};
+
+use ic_stable_structures::{
+    StableBTreeMap,
+    DefaultMemoryImpl,
+    memory_manager::VirtualMemory,
+    storable::{Storable, Bound},
+};
+
+use crate::localkey::refcell::{with, with_mut};
+use crate::{Compression, NAMED_SNAPSHOTS_MEMORY_ID, get_virtual_memory};
+
+
+/// The maximum length of the label of a named state-snapshot, in bytes.
+pub const NAMED_SNAPSHOT_LABEL_MAX_LENGTH: usize = 64;
+
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+const INFO_PART: u32 = 0;
+
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone)]
+struct NamedSnapshotKey {
+    memory_id: u8,
+    label: String,
+    part: u32,
+}
+
+impl Storable for NamedSnapshotKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut b: Vec<u8> = Vec::with_capacity(6 + self.label.len());
+        b.push(self.memory_id);
+        b.push(self.label.len() as u8);
+        b.extend_from_slice(self.label.as_bytes());
+        b.extend_from_slice(&self.part.to_be_bytes());
+        Cow::Owned(b)
+    }
+    fn from_bytes(b: Cow<'_, [u8]>) -> Self {
+        let label_end: usize = 2 + b[1] as usize;
+        Self {
+            memory_id: b[0],
+            label: String::from_utf8(b[2..label_end].to_vec()).unwrap(),
+            part: u32::from_be_bytes(b[label_end..label_end + 4].try_into().unwrap()),
+        }
+    }
+    const BOUND: Bound = Bound::Bounded{ max_size: 6 + NAMED_SNAPSHOT_LABEL_MAX_LENGTH as u32, is_fixed_size: false };
+}
+
+
+#[derive(Clone)]
+pub(crate) struct NamedSnapshotInfo {
+    pub compression: Compression,
+    pub timestamp_nanos: u64,
+    pub length: u64,
+    pub sha256: [u8; 32],
+}
+
+impl NamedSnapshotInfo {
+    fn encode(&self) -> Vec<u8> {
+        let mut b: Vec<u8> = Vec::with_capacity(49);
+        b.push(self.compression.id());
+        b.extend_from_slice(&self.timestamp_nanos.to_be_bytes());
+        b.extend_from_slice(&self.length.to_be_bytes());
+        b.extend_from_slice(&self.sha256);
+        b
+    }
+    fn decode(b: &[u8]) -> Self {
+        Self {
+            compression: Compression::from_id(b[0]).unwrap_or_else(|| ic_cdk::trap(&format!("unknown compression id: {}", b[0]))),
+            timestamp_nanos: u64::from_be_bytes(b[1..9].try_into().unwrap()),
+            length: u64::from_be_bytes(b[9..17].try_into().unwrap()),
+            sha256: b[17..49].try_into().unwrap(),
+        }
+    }
+}
+
+type NamedSnapshots = StableBTreeMap<NamedSnapshotKey, Vec<u8>, VirtualMemory<DefaultMemoryImpl>>;
+
+thread_local!{
+    static NAMED_SNAPSHOTS: RefCell<NamedSnapshots> = RefCell::new(NamedSnapshots::init(get_virtual_memory(NAMED_SNAPSHOTS_MEMORY_ID)));
+}
+
+
+fn key(memory_id: u8, label: &str, part: u32) -> NamedSnapshotKey {
+    NamedSnapshotKey{ memory_id, label: label.to_string(), part }
+}
+
+pub(crate) fn check_label(label: &str) -> Result<(), String> {
+    if label.len() > NAMED_SNAPSHOT_LABEL_MAX_LENGTH {
+        return Err(format!("the label is longer than {} bytes", NAMED_SNAPSHOT_LABEL_MAX_LENGTH));
+    }
+    Ok(())
+}
+
+/// Stores the named snapshot, replaces the snapshot with the same label.
+pub(crate) fn insert_named_snapshot(memory_id: u8, label: &str, info: NamedSnapshotInfo, bytes: &[u8]) -> Result<(), String> {
+    check_label(label)?;
+    remove_named_snapshot(memory_id, label);
+    with_mut(&NAMED_SNAPSHOTS, |named_snapshots| {
+        named_snapshots.insert(key(memory_id, label, INFO_PART), info.encode());
+        for (i, chunk) in bytes.chunks(CHUNK_SIZE).enumerate() {
+            named_snapshots.insert(key(memory_id, label, i as u32 + 1), chunk.to_vec());
+        }
+    });
+    Ok(())
+}
+
+pub(crate) fn get_named_snapshot_info(memory_id: u8, label: &str) -> Option<NamedSnapshotInfo> {
+    check_label(label).ok()?;
+    with(&NAMED_SNAPSHOTS, |named_snapshots| {
+        named_snapshots.get(&key(memory_id, label, INFO_PART)).map(|b| NamedSnapshotInfo::decode(&b))
+    })
+}
+
+/// Reads `length` bytes of the named snapshot at the `offset`, or less at the end of the snapshot.
+pub(crate) fn read_named_snapshot(memory_id: u8, label: &str, offset: u64, length: u64) -> Option<Vec<u8>> {
+    let info: NamedSnapshotInfo = get_named_snapshot_info(memory_id, label)?;
+    let end: u64 = std::cmp::min(offset.saturating_add(length), info.length);
+    let mut b: Vec<u8> = Vec::new();
+    let mut position: u64 = offset;
+    with(&NAMED_SNAPSHOTS, |named_snapshots| {
+        while position < end {
+            let part: u32 = (position / CHUNK_SIZE as u64) as u32 + 1;
+            let chunk: Vec<u8> = named_snapshots.get(&key(memory_id, label, part)).unwrap();
+            let chunk_start: usize = (position % CHUNK_SIZE as u64) as usize;
+            let chunk_end: usize = std::cmp::min(chunk.len() as u64, chunk_start as u64 + (end - position)) as usize;
+            b.extend_from_slice(&chunk[chunk_start..chunk_end]);
+            position += (chunk_end - chunk_start) as u64;
+        }
+    });
+    Some(b)
+}
+
+pub(crate) fn read_whole_named_snapshot(memory_id: u8, label: &str) -> Option<(NamedSnapshotInfo, Vec<u8>)> {
+    let info: NamedSnapshotInfo = get_named_snapshot_info(memory_id, label)?;
+    let bytes: Vec<u8> = read_named_snapshot(memory_id, label, 0, info.length)?;
+    Some((info, bytes))
+}
+
+/// Returns `false` when there is no snapshot with the label.
+pub(crate) fn remove_named_snapshot(memory_id: u8, label: &str) -> bool {
+    let info: NamedSnapshotInfo = match get_named_snapshot_info(memory_id, label) {
+        Some(info) => info,
+        None => return false,
+    };
+    let chunks_count: u32 = info.length.div_ceil(CHUNK_SIZE as u64) as u32;
+    with_mut(&NAMED_SNAPSHOTS, |named_snapshots| {
+        for part in INFO_PART..=chunks_count {
+            named_snapshots.remove(&key(memory_id, label, part));
+        }
+    });
+    true
+}
+
+/// The labels and the infos of the named snapshots of the memory-id, in the order of the labels.
+pub(crate) fn list_named_snapshots(memory_id: u8) -> Vec<(String, NamedSnapshotInfo)> {
+    let mut list: Vec<(String, NamedSnapshotInfo)> = Vec::new();
+    with(&NAMED_SNAPSHOTS, |named_snapshots| {
+        let mut opt_next = named_snapshots.range((Included(key(memory_id, "", INFO_PART)), Unbounded)).next();
+        while let Some((key, b)) = opt_next {
+            if key.memory_id != memory_id {
+                break;
+            }
+            // the entry after the last chunk of a label is the info entry of the next label. 
+            opt_next = named_snapshots.range((Excluded(NamedSnapshotKey{ part: u32::MAX, ..key.clone() }), Unbounded)).next();
+            list.push((key.label, NamedSnapshotInfo::decode(&b)));
+        }
+    });
+    list
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_bytes(length: usize) -> Vec<u8> {
+        (0..length).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn insert_test_snapshot(memory_id: u8, label: &str, bytes: &[u8]) {
+        let info = NamedSnapshotInfo{
+            compression: Compression::None,
+            timestamp_nanos: 0,
+            length: bytes.len() as u64,
+            sha256: crate::header::sha256(bytes),
+        };
+        insert_named_snapshot(memory_id, label, info, bytes).unwrap();
+    }
+
+    fn entries_count() -> u64 {
+        with(&NAMED_SNAPSHOTS, |named_snapshots| named_snapshots.len())
+    }
+
+    #[test]
+    fn read_across_the_chunks() {
+        let bytes: Vec<u8> = test_bytes(2 * CHUNK_SIZE + 10);
+        insert_test_snapshot(0, "before", &bytes);
+        assert_eq!(entries_count(), 4);
+        assert_eq!(read_named_snapshot(0, "before", CHUNK_SIZE as u64 - 5, 10), Some(bytes[CHUNK_SIZE - 5..CHUNK_SIZE + 5].to_vec()));
+        assert_eq!(read_named_snapshot(0, "before", 0, 3 * CHUNK_SIZE as u64), Some(bytes.clone()));
+        // the read stops at the end of the snapshot.
+        assert_eq!(read_named_snapshot(0, "before", 2 * CHUNK_SIZE as u64, 100), Some(bytes[2 * CHUNK_SIZE..].to_vec()));
+        assert_eq!(read_named_snapshot(0, "before", bytes.len() as u64 + 1, 100), Some(Vec::new()));
+        let (info, whole) = read_whole_named_snapshot(0, "before").unwrap();
+        assert_eq!(info.sha256, crate::header::sha256(&bytes));
+        assert!(whole == bytes);
+        assert_eq!(read_named_snapshot(0, "after", 0, 10), None);
+    }
+
+    #[test]
+    fn replace_and_remove_the_chunks() {
+        insert_test_snapshot(0, "before", &test_bytes(2 * CHUNK_SIZE + 10));
+        insert_test_snapshot(0, "before", &test_bytes(10));
+        assert_eq!(entries_count(), 2);
+        assert_eq!(read_whole_named_snapshot(0, "before").unwrap().1, test_bytes(10));
+        assert!(remove_named_snapshot(0, "before"));
+        assert!(!remove_named_snapshot(0, "before"));
+        assert_eq!(entries_count(), 0);
+    }
+
+    #[test]
+    fn list_the_labels_of_a_memory_id() {
+        insert_test_snapshot(1, "b", &test_bytes(CHUNK_SIZE + 1));
+        insert_test_snapshot(1, "a", &test_bytes(5));
+        insert_test_snapshot(1, "ab", &test_bytes(0));
+        insert_test_snapshot(0, "c", &test_bytes(5));
+        insert_test_snapshot(2, "d", &test_bytes(5));
+        let list: Vec<(String, u64)> = list_named_snapshots(1).into_iter().map(|(label, info)| (label, info.length)).collect();
+        assert_eq!(list, vec![("a".to_string(), 5), ("ab".to_string(), 0), ("b".to_string(), CHUNK_SIZE as u64 + 1)]);
+        assert_eq!(list_named_snapshots(0).len(), 1);
+        assert!(list_named_snapshots(3).is_empty());
+    }
+
+    #[test]
+    fn label_that_is_too_long() {
+        let label: String = "a".repeat(NAMED_SNAPSHOT_LABEL_MAX_LENGTH + 1);
+        let info = NamedSnapshotInfo{ compression: Compression::None, timestamp_nanos: 0, length: 0, sha256: [0; 32] };
+        assert!(insert_named_snapshot(0, &label, info, &[]).is_err());
+        assert!(get_named_snapshot_info(0, &label).is_none());
+        insert_test_snapshot(0, &label[1..], &test_bytes(5));
+        assert_eq!(list_named_snapshots(0).len(), 1);
+    }
+}
@@ -0,0 +1,130 @@
+// The rollback points that controller_load_state_snapshot takes before it loads a snapshot onto a global variable.
+// The rollback points are stored in a StableBTreeMap in the reserved ROLLBACK_POINTS_MEMORY_ID, 
+// keyed by the memory-id of the global variable and a sequence number.
+
+use std::cell::RefCell;
+use std::borrow::Cow;
+
+use ic_stable_structures::{
+    StableBTreeMap,
+    DefaultMemoryImpl,
+    memory_manager::VirtualMemory,
+    storable::{Storable, Bound},
+};
+
+use crate::localkey::refcell::with_mut;
+use crate::{Compression, ROLLBACK_POINTS_MEMORY_ID, get_virtual_memory};
+
+
+pub(crate) struct RollbackPoint {
+    pub compression: Compression,
+    pub timestamp_nanos: u64,
+    pub bytes: Vec<u8>,
+}
+
+impl Storable for RollbackPoint {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut b: Vec<u8> = Vec::with_capacity(9 + self.bytes.len());
+        b.push(self.compression.id());
+        b.extend_from_slice(&self.timestamp_nanos.to_be_bytes());
+        b.extend_from_slice(&self.bytes);
+        Cow::Owned(b)
+    }
+    fn from_bytes(b: Cow<'_, [u8]>) -> Self {
+        Self {
+            compression: Compression::from_id(b[0]).unwrap_or_else(|| ic_cdk::trap(&format!("unknown compression id: {}", b[0]))),
+            timestamp_nanos: u64::from_be_bytes(b[1..9].try_into().unwrap()),
+            bytes: b[9..].to_vec(),
+        }
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+type RollbackPoints = StableBTreeMap<(u8, u64), RollbackPoint, VirtualMemory<DefaultMemoryImpl>>;
+
+thread_local!{
+    static ROLLBACK_POINTS: RefCell<RollbackPoints> = RefCell::new(RollbackPoints::init(get_virtual_memory(ROLLBACK_POINTS_MEMORY_ID)));
+}
+
+
+/// Stores a rollback point for the memory-id and drops the oldest rollback points of the memory-id over the `limit`.
+pub(crate) fn push_rollback_point(id: u8, limit: u32, rollback_point: RollbackPoint) {
+    with_mut(&ROLLBACK_POINTS, |rollback_points| {
+        // the keys are iterated without reading the rollback points.
+        let sequence: u64 = rollback_points.keys_range((id, 0)..=(id, u64::MAX)).next_back().map(|(_, sequence)| sequence + 1).unwrap_or(0);
+        if limit > 0 {
+            rollback_points.insert((id, sequence), rollback_point);
+        }
+        let keys: Vec<(u8, u64)> = rollback_points.keys_range((id, 0)..=(id, u64::MAX)).collect();
+        for key in keys.iter().take(keys.len().saturating_sub(limit as usize)) {
+            rollback_points.remove(key);
+        }
+    });
+}
+
+/// Removes and returns the newest rollback point of the memory-id with its sequence number.
+pub(crate) fn pop_rollback_point(id: u8) -> Option<(u64, RollbackPoint)> {
+    with_mut(&ROLLBACK_POINTS, |rollback_points| {
+        let key: (u8, u64) = rollback_points.keys_range((id, 0)..=(id, u64::MAX)).next_back()?;
+        rollback_points.remove(&key).map(|rollback_point| (key.1, rollback_point))
+    })
+}
+
+/// Puts a rollback point that was popped back in its place.
+pub(crate) fn put_back_rollback_point(id: u8, sequence: u64, rollback_point: RollbackPoint) {
+    with_mut(&ROLLBACK_POINTS, |rollback_points| {
+        rollback_points.insert((id, sequence), rollback_point);
+    });
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::localkey::refcell::with;
+
+    fn test_rollback_point(timestamp_nanos: u64) -> RollbackPoint {
+        RollbackPoint{ compression: Compression::None, timestamp_nanos, bytes: vec![timestamp_nanos as u8; 10] }
+    }
+
+    fn rollback_point_timestamps(id: u8) -> Vec<u64> {
+        with(&ROLLBACK_POINTS, |rollback_points| {
+            rollback_points.range((id, 0)..=(id, u64::MAX)).map(|(_, rollback_point)| rollback_point.timestamp_nanos).collect()
+        })
+    }
+
+    #[test]
+    fn push_trims_to_the_limit() {
+        for timestamp_nanos in 1..=5 {
+            push_rollback_point(3, 2, test_rollback_point(timestamp_nanos));
+        }
+        assert_eq!(rollback_point_timestamps(3), vec![4, 5]);
+        push_rollback_point(3, 1, test_rollback_point(6));
+        assert_eq!(rollback_point_timestamps(3), vec![6]);
+        push_rollback_point(3, 0, test_rollback_point(7));
+        assert_eq!(rollback_point_timestamps(3), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn push_keeps_the_rollback_points_of_the_other_memory_ids() {
+        push_rollback_point(1, 1, test_rollback_point(1));
+        push_rollback_point(2, 1, test_rollback_point(2));
+        push_rollback_point(1, 1, test_rollback_point(3));
+        assert_eq!(rollback_point_timestamps(1), vec![3]);
+        assert_eq!(rollback_point_timestamps(2), vec![2]);
+    }
+
+    #[test]
+    fn pop_the_newest_and_put_it_back() {
+        push_rollback_point(4, 3, test_rollback_point(1));
+        push_rollback_point(4, 3, test_rollback_point(2));
+        let (sequence, rollback_point) = pop_rollback_point(4).unwrap();
+        assert_eq!((rollback_point.timestamp_nanos, rollback_point.bytes), (2, vec![2; 10]));
+        assert_eq!(rollback_point_timestamps(4), vec![1]);
+        put_back_rollback_point(4, sequence, test_rollback_point(2));
+        assert_eq!(rollback_point_timestamps(4), vec![1, 2]);
+        pop_rollback_point(4);
+        pop_rollback_point(4);
+        assert!(pop_rollback_point(4).is_none());
+    }
+}
@@ -0,0 +1,279 @@
+use std::io;
+use std::rc::Rc;
+use std::cell::Cell;
+
+use sha2::{Sha256, Digest};
+
+use ic_stable_structures::{
+    Memory,
+    DefaultMemoryImpl,
+    memory_manager::{MemoryId, VirtualMemory},
+};
+
+use ic_cdk::api::stable::WASM_PAGE_SIZE_IN_BYTES;
+
+use crate::{locate_minimum_memory, get_virtual_memory};
+
+
+/// A [Write](std::io::Write) and [Seek](std::io::Seek) adapter over the stable memory of a memory-id.
+/// The memory grows as needed with [locate_minimum_memory].
+///
+/// Don't write into the memory-ids that are registered for the upgrades, the library writes the upgrade payloads there. 
+///
+/// ## Sample
+/// ```ignore
+/// let mut writer = StableWriter::new(MemoryId::new(5));
+/// ciborium::into_writer(&data, &mut writer).unwrap();
+/// let length: u64 = writer.offset();
+/// ```
+pub struct StableWriter {
+    memory: VirtualMemory<DefaultMemoryImpl>,
+    offset: u64,
+}
+
+impl StableWriter {
+    /// Creates a writer at the start of the memory-id's stable memory.
+    pub fn new(memory_id: MemoryId) -> Self {
+        Self::with_offset(memory_id, 0)
+    }
+    /// Creates a writer at the given byte offset of the memory-id's stable memory.
+    pub fn with_offset(memory_id: MemoryId, offset: u64) -> Self {
+        Self::from_virtual_memory(get_virtual_memory(memory_id), offset)
+    }
+    pub(crate) fn from_virtual_memory(memory: VirtualMemory<DefaultMemoryImpl>, offset: u64) -> Self {
+        Self { memory, offset }
+    }
+    /// The byte offset of the next write.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+}
+
+impl io::Write for StableWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        locate_minimum_memory(&self.memory, self.offset + buf.len() as u64)
+            .map_err(|_| io::Error::new(io::ErrorKind::OutOfMemory, "stable memory grow failed"))?;
+        self.memory.write(self.offset, buf);
+        self.offset += buf.len() as u64;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl io::Seek for StableWriter {
+    /// Seeking past the end of the memory is allowed, the memory grows on the next write.
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.offset = seek_offset(&self.memory, self.offset, pos)?;
+        Ok(self.offset)
+    }
+}
+
+
+/// A [Read](std::io::Read) and [Seek](std::io::Seek) adapter over the stable memory of a memory-id.
+/// The end of the reader is the end of the memory-id's stable memory.
+///
+/// ## Sample
+/// ```ignore
+/// let data: Data = ciborium::from_reader(StableReader::new(MemoryId::new(5))).unwrap();
+/// ```
+pub struct StableReader {
+    memory: VirtualMemory<DefaultMemoryImpl>,
+    offset: u64,
+}
+
+impl StableReader {
+    /// Creates a reader at the start of the memory-id's stable memory.
+    pub fn new(memory_id: MemoryId) -> Self {
+        Self::with_offset(memory_id, 0)
+    }
+    /// Creates a reader at the given byte offset of the memory-id's stable memory.
+    pub fn with_offset(memory_id: MemoryId, offset: u64) -> Self {
+        Self::from_virtual_memory(get_virtual_memory(memory_id), offset)
+    }
+    pub(crate) fn from_virtual_memory(memory: VirtualMemory<DefaultMemoryImpl>, offset: u64) -> Self {
+        Self { memory, offset }
+    }
+    /// The byte offset of the next read.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+}
+
+impl io::Read for StableReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n: usize = std::cmp::min(buf.len() as u64, memory_size_bytes(&self.memory).saturating_sub(self.offset)) as usize;
+        self.memory.read(self.offset, &mut buf[..n]);
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+impl io::Seek for StableReader {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.offset = seek_offset(&self.memory, self.offset, pos)?;
+        Ok(self.offset)
+    }
+}
+
+
+fn memory_size_bytes(memory: &VirtualMemory<DefaultMemoryImpl>) -> u64 {
+    memory.size() * WASM_PAGE_SIZE_IN_BYTES as u64
+}
+
+fn seek_offset(memory: &VirtualMemory<DefaultMemoryImpl>, current_offset: u64, pos: io::SeekFrom) -> io::Result<u64> {
+    let (base, delta): (u64, i64) = match pos {
+        io::SeekFrom::Start(offset) => return Ok(offset),
+        io::SeekFrom::End(delta) => (memory_size_bytes(memory), delta),
+        io::SeekFrom::Current(delta) => (current_offset, delta),
+    };
+    base.checked_add_signed(delta).ok_or(io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to a negative or overflowing position"))
+}
+
+
+// Counts and hashes the bytes that pass through into the inner writer.
+pub(crate) struct HashingWriter<W: io::Write> {
+    inner: W,
+    hasher: Sha256,
+    length: u64,
+}
+
+impl<W: io::Write> HashingWriter<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        Self { inner, hasher: Sha256::new(), length: 0 }
+    }
+    pub(crate) fn finish(self) -> (W, u64, [u8; 32]) {
+        (self.inner, self.length, self.hasher.finalize().into())
+    }
+}
+
+impl<W: io::Write> io::Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n: usize = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.length += n as u64;
+        Ok(n)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+
+// Fails the writes past `limit` bytes and sets the `exceeded` flag, 
+// so that the caller can tell a full region apart from the other errors. 
+pub(crate) struct LimitedWriter<W: io::Write> {
+    inner: W,
+    remaining: u64,
+    exceeded: Rc<Cell<bool>>,
+}
+
+impl<W: io::Write> LimitedWriter<W> {
+    pub(crate) fn new(inner: W, limit: u64, exceeded: Rc<Cell<bool>>) -> Self {
+        Self { inner, remaining: limit, exceeded }
+    }
+}
+
+impl<W: io::Write> io::Write for LimitedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() as u64 > self.remaining {
+            self.exceeded.set(true);
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "the payload does not fit in the stable memory region"));
+        }
+        let n: usize = self.inner.write(buf)?;
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Counts the bytes that are read from the inner reader.
+pub(crate) struct CountingReader<R: io::Read> {
+    inner: R,
+    length: u64,
+}
+impl<R: io::Read> CountingReader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        Self { inner, length: 0 }
+    }
+    pub(crate) fn length(&self) -> u64 {
+        self.length
+    }
+}
+impl<R: io::Read> io::Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n: usize = self.inner.read(buf)?;
+        self.length += n as u64;
+        Ok(n)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write, Seek, SeekFrom};
+
+    #[test]
+    fn writer_grows_the_memory() {
+        let mut writer = StableWriter::new(MemoryId::new(0));
+        assert_eq!(get_virtual_memory(MemoryId::new(0)).size(), 0);
+        writer.write_all(&[1; 10]).unwrap();
+        assert_eq!(get_virtual_memory(MemoryId::new(0)).size(), 1);
+        // seek past the end, the next write grows the memory.
+        writer.seek(SeekFrom::Start(2 * WASM_PAGE_SIZE_IN_BYTES as u64 + 5)).unwrap();
+        writer.write_all(&[2; 10]).unwrap();
+        assert_eq!(writer.offset(), 2 * WASM_PAGE_SIZE_IN_BYTES as u64 + 15);
+        assert_eq!(get_virtual_memory(MemoryId::new(0)).size(), 3);
+        // the memory of the other memory-ids does not grow.
+        assert_eq!(get_virtual_memory(MemoryId::new(1)).size(), 0);
+    }
+
+    #[test]
+    fn reader_reads_what_the_writer_wrote() {
+        let mut writer = StableWriter::with_offset(MemoryId::new(0), 3);
+        writer.write_all(b"hello stable memory").unwrap();
+        let mut reader = StableReader::with_offset(MemoryId::new(0), 3);
+        let mut buf = [0; 5];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        reader.seek(SeekFrom::Current(1)).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"stabl");
+        assert_eq!(reader.offset(), 3 + 11);
+    }
+
+    #[test]
+    fn reader_ends_at_the_end_of_the_memory() {
+        StableWriter::new(MemoryId::new(0)).write_all(&[1; 10]).unwrap();
+        let mut reader = StableReader::new(MemoryId::new(0));
+        assert_eq!(reader.seek(SeekFrom::End(-4)).unwrap(), WASM_PAGE_SIZE_IN_BYTES as u64 - 4);
+        let mut rest: Vec<u8> = Vec::new();
+        assert_eq!(reader.read_to_end(&mut rest).unwrap(), 4);
+        assert_eq!(reader.read(&mut [0; 8]).unwrap(), 0);
+    }
+
+    #[test]
+    fn seek_to_a_negative_position() {
+        let mut reader = StableReader::with_offset(MemoryId::new(0), 4);
+        assert_eq!(reader.seek(SeekFrom::Current(-5)).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(reader.offset(), 4);
+        assert_eq!(StableWriter::new(MemoryId::new(0)).seek(SeekFrom::End(-1)).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn hashing_and_limited_writers() {
+        let exceeded: Rc<Cell<bool>> = Rc::new(Cell::new(false));
+        let mut writer = HashingWriter::new(LimitedWriter::new(Vec::new(), 8, exceeded.clone()));
+        writer.write_all(&[1; 8]).unwrap();
+        assert!(!exceeded.get());
+        assert_eq!(writer.write(&[1]).unwrap_err().kind(), io::ErrorKind::WriteZero);
+        assert!(exceeded.get());
+        let (_, length, checksum) = writer.finish();
+        assert_eq!(length, 8);
+        assert_eq!(checksum, <[u8; 32]>::from(Sha256::digest([1; 8])));
+    }
+}
@@ -1,6 +1,10 @@
 use std::cell::RefCell;
 use std::thread::LocalKey;
 use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::any::Any;
+use std::rc::Rc;
+use std::cell::Cell;
 
 use ic_cdk::{
     caller,
@@ -16,6 +20,7 @@ use ic_cdk::{
 };
 
 use candid::{CandidType, Deserialize};
+use sha2::{Sha256, Digest};
 use serde_bytes::{ByteBuf, Bytes};
         
 use ic_stable_structures::{
@@ -27,14 +32,80 @@ pub use ic_stable_structures::memory_manager::MemoryId;
 
 
 
-use crate::localkey::refcell::{with, with_mut};
+use crate::localkey::refcell::{with, with_mut, track_generation, generation};
+use crate::{UpgradeError, ControllerError, Migrations, Compression, time, instruction_counter};
+use crate::compression::{
+    compress_into,
+    decompress_from,
+    compress_bytes,
+    decompress_bytes,
+};
+use crate::header::{
+    PayloadHeader,
+    SerializationFormat,
+    MigrationRecord,
+    write_header,
+    read_header,
+    type_fingerprint,
+    sha256,
+};
+use crate::rollback::{
+    RollbackPoint,
+    push_rollback_point,
+    pop_rollback_point,
+    put_back_rollback_point,
+};
+use crate::named_snapshots::{
+    NamedSnapshotInfo,
+    NAMED_SNAPSHOT_LABEL_MAX_LENGTH,
+    check_label,
+    insert_named_snapshot,
+    get_named_snapshot_info,
+    read_named_snapshot,
+    read_whole_named_snapshot,
+    remove_named_snapshot,
+    list_named_snapshots,
+};
+use crate::chunked_upload::ChunkedUpload;
+use crate::upload_sessions::{UploadSession, ByteRange};
+use crate::upload_staging::{
+    StagedReader,
+    store_staged_session,
+    get_staged_session,
+    write_staged,
+    clear_staged,
+};
+use crate::stable_io::{
+    StableWriter,
+    StableReader,
+    HashingWriter,
+    LimitedWriter,
+    CountingReader,
+};
 
 
 /// A trait that specifies how the data structure will be serialized for the upgrades and for the snapshots.
 /// This trait is implemented with the [candid](https://docs.rs/candid/latest/candid/index.html) serialization format for any type that implements the CandidType and Deserialize traits.
+///
+/// The upgrades use the streaming methods [forward_into](Serializable::forward_into) and [backward_from](Serializable::backward_from), 
+/// which write straight into and read straight from the stable memory. 
+/// Their default implementations go through [forward](Serializable::forward) and [backward](Serializable::backward) with a buffer in the heap. 
+/// Override them for a format that can stream so that the whole payload never has to sit in the heap.
 pub trait Serializable {
     fn forward(&self) -> Result<Vec<u8>, String>;
     fn backward(b: &[u8]) -> Result<Self, String> where Self: Sized;     
+    fn forward_into(&self, w: &mut dyn Write) -> Result<(), String> {
+        w.write_all(&self.forward()?).map_err(|e| format!("{:?}", e))
+    }
+    fn backward_from(r: &mut dyn Read) -> Result<Self, String> where Self: Sized {
+        let mut b: Vec<u8> = Vec::new();
+        r.read_to_end(&mut b).map_err(|e| format!("{:?}", e))?;
+        Self::backward(&b)
+    }
+    /// The format that is recorded in the stable-memory header of the upgrade payload.
+    fn format() -> SerializationFormat where Self: Sized {
+        SerializationFormat::Custom
+    }
 }
 
 impl<T: CandidType + for<'a> Deserialize<'a>> Serializable for T {
@@ -44,19 +115,206 @@ impl<T: CandidType + for<'a> Deserialize<'a>> Serializable for T {
     fn backward(b: &[u8]) -> Result<Self, String> {
         candid::decode_one(b).map_err(|e| format!("{:?}", e))
     }
+    fn format() -> SerializationFormat {
+        SerializationFormat::Candid
+    }
+}
+
+
+/// A serialization strategy for a global variable, chosen per registration with 
+/// [init_with_codec], [post_upgrade_with_codec], and [Migrations::new_with_codec].
+///
+/// Because of the candid blanket implementation of [Serializable], a type that implements `CandidType` can't have 
+/// a hand-written [Serializable] implementation. Implement this trait on a separate type instead, 
+/// the global variable can then keep its `CandidType` implementation for the canister methods. 
+/// 
+/// The functions without a codec parameter use [SerializableCodec].
+///
+/// ## Sample
+/// ```ignore
+/// struct FastCodec;
+/// impl UpgradeCodec<Data> for FastCodec {
+///     fn forward(data: &Data) -> Result<Vec<u8>, String> { ... }
+///     fn backward(b: &[u8]) -> Result<Data, String> { ... }
+/// }
+///
+/// #[init]
+/// fn init() {
+///     canister_tools::init_with_codec::<Data, FastCodec>(&DATA, DATA_UPGRADE_MEMORY_ID);
+/// }
+///
+/// #[post_upgrade]
+/// fn post_upgrade() {
+///     canister_tools::post_upgrade_with_codec::<Data, FastCodec>(&DATA, DATA_UPGRADE_MEMORY_ID);
+/// }
+/// ```
+pub trait UpgradeCodec<T> {
+    fn forward(data: &T) -> Result<Vec<u8>, String>;
+    fn backward(b: &[u8]) -> Result<T, String>;
+    fn forward_into(data: &T, w: &mut dyn Write) -> Result<(), String> {
+        w.write_all(&Self::forward(data)?).map_err(|e| format!("{:?}", e))
+    }
+    fn backward_from(r: &mut dyn Read) -> Result<T, String> {
+        let mut b: Vec<u8> = Vec::new();
+        r.read_to_end(&mut b).map_err(|e| format!("{:?}", e))?;
+        Self::backward(&b)
+    }
+    /// The format that is recorded in the stable-memory header of the upgrade payload.
+    fn format() -> SerializationFormat {
+        SerializationFormat::Custom
+    }
+}
+
+/// The default [UpgradeCodec], uses the [Serializable] implementation of the type. 
+/// This is candid for the types that implement `CandidType` and `Deserialize`.
+pub struct SerializableCodec;
+
+impl<T: Serializable> UpgradeCodec<T> for SerializableCodec {
+    fn forward(data: &T) -> Result<Vec<u8>, String> {
+        <T as Serializable>::forward(data)
+    }
+    fn backward(b: &[u8]) -> Result<T, String> {
+        <T as Serializable>::backward(b)
+    }
+    fn forward_into(data: &T, w: &mut dyn Write) -> Result<(), String> {
+        <T as Serializable>::forward_into(data, w)
+    }
+    fn backward_from(r: &mut dyn Read) -> Result<T, String> {
+        <T as Serializable>::backward_from(r)
+    }
+    fn format() -> SerializationFormat {
+        <T as Serializable>::format()
+    }
 }
 
 
+type DecodeBytesFn = Box<dyn Fn(&[u8]) -> Result<Box<dyn Any>, String>>;
+type SetDataFn = Box<dyn Fn(Box<dyn Any>)>;
+type SerializeDataFn = Box<dyn Fn() -> Result<Vec<u8>, String>>;
+type SerializeDataIntoFn = Box<dyn Fn(&mut dyn Write) -> Result<(), String>>;
+// decodes the data as the registered type without loading it onto the global variable.
+type DecodeDataFn = Box<dyn Fn(&mut dyn Read) -> Result<Box<dyn Any>, String>>;
+
 struct SnapshotData {
+    // the u8 of the memory-id, MemoryId has no getter for it.
+    memory_id: u8,
     snapshot: Vec<u8>,
-    load_data_fn: Box<dyn Fn(&[u8]) -> Result<(), String>>,
-    serialize_data_fn: Box<dyn Fn() -> Result<Vec<u8>, String>>,
+    snapshot_compression: Compression,
+    decode_bytes_fn: DecodeBytesFn,
+    set_data_fn: SetDataFn,
+    decode_data_fn: DecodeDataFn,
+    serialize_data_fn: SerializeDataFn,
+    serialize_data_into_fn: SerializeDataIntoFn,
+    format: SerializationFormat,
+    compression: Compression,
+    type_fingerprint: u64,
+    data_version: u32,
+    migration_log: Vec<MigrationRecord>,
+    localkey_address: usize,
+    dirty_tracking: bool,
+    // the generation of the data in the upgrade slots, when it was written by this canister version.
+    upgrade_payload_generation: Option<u64>,
+    // the generation of the data in the snapshot and whether the snapshot is compressed with the memory-id's compression. 
+    snapshot_generation: Option<(u64, bool)>,
+    rollback_limit: u32,
+    invariants: Vec<Invariant>,
+    type_name: &'static str,
+    // the sha256 of the snapshot bytes so far, updated on each append.
+    snapshot_hasher: Sha256,
+    snapshot_timestamp_nanos: Option<u64>,
+    snapshot_source: Option<StateSnapshotSource>,
+    // the chunks of an upload with controller_put_state_snapshot_chunk, kept after the join to check the chunks that arrive again.
+    upload: Option<ChunkedUpload>,
+    // the upload session that holds the snapshot buffer.
+    upload_session: Option<UploadSession>,
+    upload_staging: bool,
+}
+
+impl SnapshotData {
+    fn clear_snapshot(&mut self, snapshot_compression: Compression) {
+        self.snapshot = Vec::new();
+        self.snapshot_compression = snapshot_compression;
+        self.snapshot_generation = None;
+        self.snapshot_hasher = Sha256::new();
+        self.snapshot_timestamp_nanos = None;
+        self.snapshot_source = None;
+        self.upload = None;
+        self.upload_session = None;
+    }
+
+    fn staged_upload_session(&self) -> Option<&UploadSession> {
+        self.upload_session.as_ref().filter(|upload_session| upload_session.staged)
+    }
+
+    // The length of the snapshot, the snapshot of a staged upload session is in the staging area.
+    fn snapshot_length(&self) -> u64 {
+        match self.staged_upload_session() {
+            Some(upload_session) => upload_session.total_length,
+            None => self.snapshot.len() as u64,
+        }
+    }
+
+    fn snapshot_sha256(&self) -> [u8; 32] {
+        match self.staged_upload_session().and_then(|upload_session| upload_session.received_sha256) {
+            Some(received_sha256) => received_sha256,
+            None => self.snapshot_hasher.clone().finalize().into(),
+        }
+    }
+}
+
+/// Where the current snapshot of a memory-id came from.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StateSnapshotSource {
+    /// Created by the `controller_create_state_snapshot` or the `controller_create_raw_state_snapshot` canister method.
+    Serialization,
+    /// Uploaded with the `controller_append_state_snapshot` or the `controller_put_state_snapshot_chunk` canister method.
+    Upload,
+}
+
+type CheckDataFn = Box<dyn Fn(&dyn Any) -> Result<(), String>>;
+type CheckGlobalFn = Box<dyn Fn() -> Result<(), String>>;
+
+struct Invariant {
+    name: String,
+    // checks decoded data that is not loaded yet.
+    check_fn: CheckDataFn,
+    // checks the global variable.
+    check_global_fn: CheckGlobalFn,
+}
+
+// Runs the invariants on the data, returns the failed checks.
+fn failed_checks(invariants: &[Invariant], data: &dyn Any) -> Vec<String> {
+    invariants.iter().filter_map(|invariant| {
+        (invariant.check_fn)(data).err().map(|e| format!("{}: {}", invariant.name, e))
+    }).collect()
+}
+
+fn failed_global_checks(invariants: &[Invariant]) -> Vec<String> {
+    invariants.iter().filter_map(|invariant| {
+        (invariant.check_global_fn)().err().map(|e| format!("{}: {}", invariant.name, e))
+    }).collect()
 }
 
 type StateSnapshots = BTreeMap<MemoryId, SnapshotData>;
 
 
-const STABLE_MEMORY_HEADER_SIZE_BYTES: u64 = 1024;
+pub(crate) const STABLE_MEMORY_HEADER_SIZE_BYTES: u64 = 1024;
+
+const STABLE_MEMORY_IO_BUFFER_SIZE: usize = 64 * 1024;
+
+/// The memory-id where the library stores the rollback points of the `controller_load_state_snapshot` canister method. 
+/// Don't use this memory-id for the global variables or for the stable structures of the canister.
+pub const ROLLBACK_POINTS_MEMORY_ID: MemoryId = MemoryId::new(253);
+
+/// The memory-id where the library stores the named state-snapshots, see the `controller_create_named_state_snapshot` canister method.
+/// Don't use this memory-id for the global variables or for the stable structures of the canister.
+pub const NAMED_SNAPSHOTS_MEMORY_ID: MemoryId = MemoryId::new(254);
+
+/// The memory-id where the library stages the uploads of the upload sessions, see [set_upload_staging].
+/// Don't use this memory-id for the global variables or for the stable structures of the canister.
+pub const UPLOAD_STAGING_MEMORY_ID: MemoryId = MemoryId::new(252);
+
+const DEFAULT_ROLLBACK_LIMIT: u32 = 0;
 
 
 
@@ -64,11 +322,19 @@ thread_local!{
     
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(MemoryManager::init_with_bucket_size(DefaultMemoryImpl::default(), 1));
     
-    static STATE_SNAPSHOTS: RefCell<StateSnapshots> = RefCell::new(StateSnapshots::new());
+    static STATE_SNAPSHOTS: RefCell<StateSnapshots> = const { RefCell::new(StateSnapshots::new()) };
+
+}
 
+// MemoryId has no getter for its u8, the u8 is found with a binary search on the order of the memory-ids 0..=254.
+// The registered memory-ids keep their u8, see SnapshotData::memory_id.
+fn memory_id_as_u8(memory_id: MemoryId) -> u8 {
+    let ids: [u8; u8::MAX as usize] = std::array::from_fn(|i| i as u8);
+    ids.partition_point(|i| MemoryId::new(*i) < memory_id) as u8
 }
 
 /// Gets the stable memory of the memory_id.  
+/// Use [StableReader] and [StableWriter] for [std::io] access to the memory.
 pub fn get_virtual_memory(memory_id: MemoryId) -> VirtualMemory<DefaultMemoryImpl> {
     with(&MEMORY_MANAGER, |mgr| mgr.get(memory_id))
 }
@@ -78,49 +344,350 @@ pub fn get_virtual_memory(memory_id: MemoryId) -> VirtualMemory<DefaultMemoryImp
 
 /// Call this function in the canister_init method. This function registers the data structure with the memory_id for the upgrades and snapshots. 
 pub fn init<Data: 'static + Serializable>(s: &'static LocalKey<RefCell<Data>>, memory_id: MemoryId) {
-    with_mut(&STATE_SNAPSHOTS, |state_snapshots| {
-        if state_snapshots.contains_key(&memory_id) {
-            trap(&format!("memory-id: {:?} is already registered with the canister-tools library.", memory_id));
+    init_with_codec::<Data, SerializableCodec>(s, memory_id);
+}
+
+/// Same as [init] but serializes the data structure with the given [UpgradeCodec].
+pub fn init_with_codec<Data: 'static, C: 'static + UpgradeCodec<Data>>(s: &'static LocalKey<RefCell<Data>>, memory_id: MemoryId) {
+    register::<Data, C>(s, memory_id, 0, Vec::new()).unwrap_or_else(|e| trap(&e.to_string()));
+    // tests that the pre-upgrade works and takes a snapshot of this first-state.
+    pre_upgrade();
+}
+
+fn check_not_registered(memory_id: MemoryId) -> Result<(), UpgradeError> {
+    if memory_id == ROLLBACK_POINTS_MEMORY_ID || memory_id == NAMED_SNAPSHOTS_MEMORY_ID || memory_id == UPLOAD_STAGING_MEMORY_ID {
+        return Err(UpgradeError::ReservedMemoryId{ memory_id });
+    }
+    with(&STATE_SNAPSHOTS, |state_snapshots| {
+        match state_snapshots.contains_key(&memory_id) {
+            true => Err(UpgradeError::AlreadyRegistered{ memory_id }),
+            false => Ok(()),
         }
+    })
+}
+
+pub(crate) type RegisterFn<Data> = fn(&'static LocalKey<RefCell<Data>>, MemoryId, u32, Vec<MigrationRecord>) -> Result<(), UpgradeError>;
+
+pub(crate) fn register<Data: 'static, C: 'static + UpgradeCodec<Data>>(s: &'static LocalKey<RefCell<Data>>, memory_id: MemoryId, data_version: u32, migration_log: Vec<MigrationRecord>) -> Result<(), UpgradeError> {
+    check_not_registered(memory_id)?;
+    with_mut(&STATE_SNAPSHOTS, |state_snapshots| {
         
         state_snapshots.insert(
             memory_id,
             SnapshotData {
+                memory_id: memory_id_as_u8(memory_id),
                 snapshot: Vec::new(),
-                load_data_fn: Box::new(move |b| {
+                snapshot_compression: Compression::None,
+                decode_bytes_fn: Box::new(|b| {
+                    C::backward(b).map(|data| Box::new(data) as Box<dyn Any>)
+                }),
+                set_data_fn: Box::new(move |new_data| {
                     with_mut(s, |data| {
-                        *data = <Data as Serializable>::backward(b)?;
-                        Ok(())
-                    })
+                        *data = *new_data.downcast::<Data>().unwrap();
+                    });
+                }),
+                decode_data_fn: Box::new(|r| {
+                    C::backward_from(r).map(|data| Box::new(data) as Box<dyn Any>)
                 }),
                 serialize_data_fn: Box::new(move || { 
                     with(s, |data| {
-                        <Data as Serializable>::forward(data)
+                        C::forward(data)
                     })
-                })
+                }),
+                serialize_data_into_fn: Box::new(move |w| { 
+                    with(s, |data| {
+                        C::forward_into(data, w)
+                    })
+                }),
+                format: C::format(),
+                compression: Compression::None,
+                type_fingerprint: type_fingerprint::<Data>(),
+                data_version,
+                migration_log,
+                localkey_address: s as *const LocalKey<RefCell<Data>> as usize,
+                dirty_tracking: false,
+                upgrade_payload_generation: None,
+                snapshot_generation: None,
+                rollback_limit: DEFAULT_ROLLBACK_LIMIT,
+                invariants: Vec::new(),
+                type_name: std::any::type_name::<Data>(),
+                snapshot_hasher: Sha256::new(),
+                snapshot_timestamp_nanos: None,
+                snapshot_source: None,
+                upload: None,
+                upload_session: None,
+                upload_staging: false,
             }
         ); 
-    });    
-    // tests that the pre-upgrade works and takes a snapshot of this first-state.
-    pre_upgrade();
+    });
+    track_generation(s as *const LocalKey<RefCell<Data>> as usize);
+    Ok(())
+}
+
+/// Sets the compression of the upgrade payloads and of the state-snapshots of the memory-id. 
+/// Call this function after the memory-id is registered, in the canister_init method and in the post_upgrade hook.
+/// The compression of a payload is recorded in the header, so the next post_upgrade reads it with any compression setting.  
+pub fn set_compression(memory_id: MemoryId, compression: Compression) {
+    if !compression.is_enabled() {
+        trap(&format!("the {:?} compression is not enabled, enable its cargo feature", compression));
+    }
+    with_mut(&STATE_SNAPSHOTS, |state_snapshots| {
+        match state_snapshots.get_mut(&memory_id) {
+            None => trap(&format!("memory-id: {:?} is not registered with the canister-tools library.", memory_id)),
+            Some(d) => {
+                if d.compression != compression {
+                    d.upgrade_payload_generation = None;
+                    d.snapshot_generation = None;
+                }
+                d.compression = compression;
+            }
+        }
+    });
+}
+
+/// Skips the serialization of the memory-id's global variable in the [pre_upgrade], in the checkpoints, 
+/// and in the `controller_create_state_snapshot` canister method when the global variable is not modified since the last serialization.
+///
+/// A modification is a call of [with_mut](crate::localkey::refcell::with_mut) on the global variable, or a load of a state-snapshot.
+/// Only enable the dirty tracking when the global variable is never modified in another way, 
+/// for example with `DATA.with(|data| data.borrow_mut())` or through a `Cell` inside the data, 
+/// because those modifications are not seen and would not be serialized.
+///
+/// Call this function after the memory-id is registered, in the canister_init method and in the post_upgrade hook.
+pub fn set_dirty_tracking(memory_id: MemoryId, enable: bool) {
+    with_mut(&STATE_SNAPSHOTS, |state_snapshots| {
+        match state_snapshots.get_mut(&memory_id) {
+            None => trap(&format!("memory-id: {:?} is not registered with the canister-tools library.", memory_id)),
+            Some(d) => {
+                d.dirty_tracking = enable;
+            }
+        }
+    });
+}
+
+/// Stages the uploads of the `controller_begin_state_snapshot_upload` canister method in the stable memory 
+/// of the reserved [UPLOAD_STAGING_MEMORY_ID] instead of the heap. The default is `false`.
+///
+/// The `controller_load_state_snapshot` canister method decodes a staged upload through a reader, 
+/// so the heap does not hold the uploaded bytes and the decoded global variable at the same time.
+/// A staged upload session continues after an upgrade, when the upload staging of the memory-id is enabled again in the post_upgrade hook.
+/// The staged bytes are freed when the session is loaded, so after the load the memory-id has no snapshot to download.
+/// The [UPLOAD_STAGING_MEMORY_ID] is not used until the upload staging of a memory-id is enabled.
+///
+/// Call this function after the memory-id is registered, in the canister_init method and in the post_upgrade hook.
+pub fn set_upload_staging(memory_id: MemoryId, enable: bool) {
+    with_mut(&STATE_SNAPSHOTS, |state_snapshots| {
+        match state_snapshots.get_mut(&memory_id) {
+            None => trap(&format!("memory-id: {:?} is not registered with the canister-tools library.", memory_id)),
+            Some(d) => {
+                d.upload_staging = enable;
+                if enable && d.upload_session.is_none() {
+                    restore_staged_upload_session(d);
+                }
+            }
+        }
+    });
+}
+
+/// Sets the number of rollback points that are kept for the memory-id, the default is 0. 
+/// 0 turns off the rollback points of the memory-id.
+///
+/// Before the `controller_load_state_snapshot` canister method loads a snapshot onto the global variable, 
+/// the current state is serialized into a rollback point in the [ROLLBACK_POINTS_MEMORY_ID]. 
+/// The `controller_rollback_state_snapshot` canister method loads the newest rollback point back onto the global variable.
+/// When there are more rollback points than the limit, the oldest rollback points are dropped.
+/// Each load serializes the current state, so a limit above 0 adds the cost of a serialization to each `controller_load_state_snapshot`.
+///
+/// Call this function after the memory-id is registered, in the canister_init method and in the post_upgrade hook.
+pub fn set_rollback_limit(memory_id: MemoryId, limit: u32) {
+    with_mut(&STATE_SNAPSHOTS, |state_snapshots| {
+        match state_snapshots.get_mut(&memory_id) {
+            None => trap(&format!("memory-id: {:?} is not registered with the canister-tools library.", memory_id)),
+            Some(d) => {
+                d.rollback_limit = limit;
+            }
+        }
+    });
+}
+
+/// Adds an invariant check of the global variable that is registered with the memory-id.
+/// The check is run right away, then on each `controller_load_state_snapshot` before the snapshot is loaded.
+/// When a snapshot does not pass a check, the load is aborted and the global variable keeps its state.
+///
+/// Call this function after the memory-id is registered, in the canister_init method and in the post_upgrade hook. 
+/// Traps when the global variable does not pass the check, so that an upgrade that loads a broken state fails 
+/// and the canister keeps its old code and state.
+///
+/// The `controller_check_invariants` canister method runs the checks on the global variable. 
+/// With the `timers` cargo feature, [set_invariant_check_interval](crate::set_invariant_check_interval) runs the checks on a timer.
+///
+/// ## Sample
+/// ```ignore
+/// #[post_upgrade]
+/// fn post_upgrade() {
+///     canister_tools::post_upgrade(&DATA, DATA_UPGRADE_MEMORY_ID, None::<fn(Data) -> Data>);
+///     canister_tools::add_invariant(&DATA, DATA_UPGRADE_MEMORY_ID, "balances-sum", |data: &Data| {
+///         if data.balances.values().sum::<u128>() != data.total_supply {
+///             return Err("the balances do not sum to the total supply".to_string());
+///         }
+///         Ok(())
+///     });
+/// }
+/// ```
+pub fn add_invariant<Data: 'static, F>(s: &'static LocalKey<RefCell<Data>>, memory_id: MemoryId, name: &str, check: F) 
+    where F: 'static + Fn(&Data) -> Result<(), String>
+    {
+    if let Err(e) = with(s, |data| check(data)) {
+        trap(&format!("memory-id: {:?}: the global variable does not pass the invariant check {}: {}", memory_id, name, e));
+    }
+    let check: Rc<F> = Rc::new(check);
+    let check_global: Rc<F> = check.clone();
+    with_mut(&STATE_SNAPSHOTS, |state_snapshots| {
+        match state_snapshots.get_mut(&memory_id) {
+            None => trap(&format!("memory-id: {:?} is not registered with the canister-tools library.", memory_id)),
+            Some(d) => {
+                if d.localkey_address != s as *const LocalKey<RefCell<Data>> as usize {
+                    trap(&format!("memory-id: {:?} is registered with a different global variable.", memory_id));
+                }
+                d.invariants.push(Invariant{
+                    name: name.to_string(),
+                    check_fn: Box::new(move |data| check(data.downcast_ref::<Data>().unwrap())),
+                    check_global_fn: Box::new(move || with(s, |data| check_global(data))),
+                });
+            }
+        }
+    });
+}
+
+// Runs the invariant checks on the global variable of the memory-id, returns the failed checks.
+pub(crate) fn check_invariants(memory_id: MemoryId) -> Result<Vec<String>, String> {
+    with(&STATE_SNAPSHOTS, |state_snapshots| {
+        match state_snapshots.get(&memory_id) {
+            None => Err(format!("memory-id: {:?} is not registered with the canister-tools library.", memory_id)),
+            Some(d) => Ok(failed_global_checks(&d.invariants)),
+        }
+    })
 }
 
 /// Call this function in the pre_upgrade hook. 
 /// Serializes each registered global variable into the corresponding stable-memory-id that it is registerd with.
+/// The first 1024 bytes of each memory-id hold a header that describes the payload. 
+/// The data is serialized straight into the stable memory, see [Serializable::forward_into].
+///
+/// Each memory-id has two payload slots. The newest payload is never overwritten, 
+/// the new payload is written into the other slot and then committed in the header.
+///
+/// The memory-ids with the [dirty tracking](set_dirty_tracking) are skipped when the data is not modified since the last write.
 pub fn pre_upgrade() {
     with_mut(&STATE_SNAPSHOTS, |state_snapshots| {
         for (memory_id, d) in state_snapshots.iter_mut() {
-            d.snapshot = Vec::new(); // clear first so don't have to hold the deserialized data and old snapshot at the same time in the heap.
-            d.snapshot = (d.serialize_data_fn)().unwrap();
-            write_data_with_length_onto_the_stable_memory(
-                &get_virtual_memory(*memory_id/*.clone()*/),
-                STABLE_MEMORY_HEADER_SIZE_BYTES,
-                &d.snapshot
-            ).unwrap();
+            // free the snapshot, the data is serialized straight onto the stable memory.
+            // a staged upload session is kept, its bytes are in the staging area and it continues after the upgrade.
+            if d.staged_upload_session().is_none() {
+                d.clear_snapshot(Compression::None);
+            }
+            write_upgrade_payload_if_modified(*memory_id, d).unwrap_or_else(|e| {
+                trap(&format!("memory-id: {:?}: error serializing the data onto the stable memory: {}", memory_id, e));
+            });
         }
     });
 }
 
+// Writes the current data of the memory-id into its upgrade slots without a running upgrade. 
+// The state-snapshot of the memory-id is kept.
+#[cfg(feature = "checkpoints")]
+pub(crate) fn write_checkpoint(memory_id: MemoryId) -> Result<(), String> {
+    with_mut(&STATE_SNAPSHOTS, |state_snapshots| {
+        match state_snapshots.get_mut(&memory_id) {
+            None => Err(format!("memory-id: {:?} is not registered with the canister-tools library.", memory_id)),
+            Some(d) => write_upgrade_payload_if_modified(memory_id, d),
+        }
+    })
+}
+
+// The u8 of the memory-id when the memory-id is registered.
+#[cfg(feature = "timers")]
+pub(crate) fn registered_memory_id(memory_id: MemoryId) -> Option<u8> {
+    with(&STATE_SNAPSHOTS, |state_snapshots| state_snapshots.get(&memory_id).map(|d| d.memory_id))
+}
+
+fn write_upgrade_payload_if_modified(memory_id: MemoryId, d: &mut SnapshotData) -> Result<(), String> {
+    let current_generation: u64 = generation(d.localkey_address).generation;
+    if d.dirty_tracking && d.upgrade_payload_generation == Some(current_generation) {
+        return Ok(());
+    }
+    write_upgrade_payload(memory_id, d)?;
+    d.upgrade_payload_generation = Some(current_generation);
+    Ok(())
+}
+
+struct PayloadPlacement {
+    opt_live: Option<(usize, PayloadHeader)>,
+    slot: usize,
+    sequence: u64,
+    // the regions where the new payload can go without touching the live payload, with the maximum payload length of each region. 
+    regions: Vec<(u64, Option<u64>)>,
+}
+
+fn next_payload_placement(serialization_memory: &VirtualMemory<DefaultMemoryImpl>, memory_id: MemoryId) -> PayloadPlacement {
+    let memory_size_bytes: u64 = serialization_memory.size() * WASM_PAGE_SIZE_IN_BYTES as u64;
+    
+    let opt_live: Option<(usize, PayloadHeader)> = read_header(serialization_memory, memory_id).ok().flatten()
+        .and_then(|slots| slots.into_iter().next())
+        .filter(|(_, live)| live.payload_offset.checked_add(8).and_then(|payload_start| payload_start.checked_add(live.payload_length)).is_some_and(|live_end| live_end <= memory_size_bytes));
+    
+    let (slot, sequence, regions) = match opt_live {
+        None => (0, 1, vec![(STABLE_MEMORY_HEADER_SIZE_BYTES, None)]),
+        Some((live_slot, ref live)) => {
+            let mut regions = Vec::new();
+            if live.payload_offset > STABLE_MEMORY_HEADER_SIZE_BYTES + 8 {
+                regions.push((STABLE_MEMORY_HEADER_SIZE_BYTES, Some(live.payload_offset - STABLE_MEMORY_HEADER_SIZE_BYTES - 8)));
+            }
+            regions.push((live.payload_offset + 8 + live.payload_length, None));
+            (1 - live_slot, live.sequence + 1, regions)
+        }
+    };
+    PayloadPlacement{ opt_live, slot, sequence, regions }
+}
+
+// Writes the data into the slot that does not hold the newest payload, then commits it in the header.
+fn write_upgrade_payload(memory_id: MemoryId, d: &SnapshotData) -> Result<(), String> {
+    let serialization_memory = get_virtual_memory(memory_id);
+    let PayloadPlacement{ opt_live, slot, sequence, regions } = next_payload_placement(&serialization_memory, memory_id);
+    
+    for (payload_offset, opt_limit) in regions {
+        // when the payload doesn't fit before the live payload, it is serialized again after the live payload.
+        if let Some((payload_length, checksum)) = write_data_with_length_onto_the_stable_memory(
+            &serialization_memory,
+            payload_offset,
+            &d.serialize_data_into_fn,
+            d.compression,
+            opt_limit,
+        )? {
+            // write the header after the payload so that a header is never pointing at a half-written payload.
+            write_header(
+                &serialization_memory,
+                slot,
+                &PayloadHeader{
+                    format_id: d.format.id(),
+                    compression_id: d.compression.id(),
+                    payload_offset,
+                    payload_length,
+                    checksum,
+                    type_fingerprint: d.type_fingerprint,
+                    timestamp_nanos: time(),
+                    data_version: d.data_version,
+                    sequence,
+                    migration_log: d.migration_log.clone(),
+                },
+                opt_live.as_ref().map(|(_, live)| live),
+            );
+            return Ok(());
+        }
+    }
+    Err("the payload does not fit in the stable memory".to_string())
+}
+
 /// Call this function in the post_upgrade_hook. 
 /// Deserializes the data stored at the memory_id and loads it onto the global variable. 
 /// Then registers the global variable with the memory_id for the next upgrade and for the state-snapshots.
@@ -130,35 +697,232 @@ pub fn pre_upgrade() {
 /// The function will deserialize the data into the old data structure type, 
 /// then convert it into the new data structure type, 
 /// and then load it onto the global variable.  
+///
+/// The header at the start of the memory-id is checked, and the sha256 of the payload is verified, before the payload is deserialized. 
+/// Payloads written by the versions of this library before the header was introduced are still loaded. 
+///
+/// Traps if the data can't be loaded. Use [try_post_upgrade] to handle the error.
 pub fn post_upgrade<Data, OldData, F>(s: &'static LocalKey<RefCell<Data>>, memory_id: MemoryId, opt_old_as_new_convert: Option<F>) 
     where 
         Data: 'static + Serializable,
         OldData: Serializable,
         F: Fn(OldData) -> Data
     {
-                
-    let stable_data: Vec<u8> = read_stable_memory_bytes_with_length(
-        &get_virtual_memory(memory_id),
-        STABLE_MEMORY_HEADER_SIZE_BYTES,
-    );
+    if let Err(e) = try_post_upgrade(s, memory_id, opt_old_as_new_convert) {
+        trap(&e.to_string());
+    }
+}
 
+/// Same as [post_upgrade] but returns an error instead of trapping. 
+/// 
+/// On an error, the global variable is not changed and is not registered with the memory_id. 
+/// The canister can then set a fallback state and call [init], 
+/// or first copy the raw payload out of the memory-id's stable memory with [get_virtual_memory] for a later recovery, 
+/// because the registration overwrites the payload.  
+pub fn try_post_upgrade<Data, OldData, F>(s: &'static LocalKey<RefCell<Data>>, memory_id: MemoryId, opt_old_as_new_convert: Option<F>) -> Result<(), UpgradeError>
+    where 
+        Data: 'static + Serializable,
+        OldData: Serializable,
+        F: Fn(OldData) -> Data
+    {
+    
+    check_not_registered(memory_id)?;
+    
+    let (opt_header, mut payload_reader) = read_verified_payload(memory_id)?;
+    
+    let migration_log: Vec<MigrationRecord> = match opt_header {
+        Some(ref header) => {
+            if header.data_version != 0 {
+                return Err(UpgradeError::UnknownDataVersion{ memory_id, data_version: header.data_version });
+            }
+            header.migration_log.clone()
+        }
+        None => Vec::new(),
+    };
+    
+    let (stored_format, stored_type_fingerprint) = match opt_old_as_new_convert {
+        Some(_) => (<OldData as Serializable>::format(), type_fingerprint::<OldData>()),
+        None => (<Data as Serializable>::format(), type_fingerprint::<Data>()),
+    };
+    
+    check_format(memory_id, &opt_header, stored_format)?;
+    
+    let decode_error = |error: String| UpgradeError::Decode{ 
+        memory_id, 
+        error, 
+        written_by_same_type: opt_header.as_ref().map(|header| header.type_fingerprint == stored_type_fingerprint),
+    };
+    
+    let new_data: Data = match opt_old_as_new_convert {
+        Some(ref old_as_new_convert) => old_as_new_convert(<OldData as Serializable>::backward_from(&mut payload_reader).map_err(decode_error)?),
+        None => <Data as Serializable>::backward_from(&mut payload_reader).map_err(decode_error)?,
+    };
+    
     with_mut(s, |data| {
-        *data = match opt_old_as_new_convert {
-            Some(ref old_as_new_convert) => old_as_new_convert(<OldData as Serializable>::backward(&stable_data).unwrap()),
-            None => <Data as Serializable>::backward(&stable_data).unwrap(),
-        };
+        *data = new_data;
     });
     
     // portant!
-    init(s, memory_id);
+    register::<Data, SerializableCodec>(s, memory_id, 0, migration_log)?;
+    pre_upgrade();
+    
+    Ok(())
+}
+
+
+/// Same as [post_upgrade] without the `opt_old_as_new_convert` parameter, but deserializes the data with the given [UpgradeCodec].
+///
+/// Traps if the data can't be loaded. Use [try_post_upgrade_with_codec] to handle the error.
+pub fn post_upgrade_with_codec<Data: 'static, C: 'static + UpgradeCodec<Data>>(s: &'static LocalKey<RefCell<Data>>, memory_id: MemoryId) {
+    if let Err(e) = try_post_upgrade_with_codec::<Data, C>(s, memory_id) {
+        trap(&e.to_string());
+    }
+}
+
+/// Same as [post_upgrade_with_codec] but returns an error instead of trapping.
+pub fn try_post_upgrade_with_codec<Data: 'static, C: 'static + UpgradeCodec<Data>>(s: &'static LocalKey<RefCell<Data>>, memory_id: MemoryId) -> Result<(), UpgradeError> {
+    // a chain with a single data version 0.
+    try_post_upgrade_with_migrations(s, memory_id, Migrations::<Data>::new_with_codec::<C>())
+}
+
+
+/// Call this function in the canister_init method when the global variable is upgraded with [post_upgrade_with_migrations]. 
+/// Registers the global variable with the memory_id at the latest data version of the migrations. 
+pub fn init_with_migrations<Data: 'static>(s: &'static LocalKey<RefCell<Data>>, memory_id: MemoryId, migrations: &Migrations<Data>) {
+    (migrations.register_fn())(s, memory_id, migrations.latest_version(), Vec::new()).unwrap_or_else(|e| trap(&e.to_string()));
+    pre_upgrade();
+}
+
+/// Call this function in the post_upgrade_hook instead of [post_upgrade] to load a payload that may be a few data versions behind.
+/// Reads the data version in the header, deserializes the payload as the type of that version, 
+/// and then applies the remaining migration steps in order.
+/// Each applied step is recorded in the header for the next upgrade. 
+///
+/// Traps if the data can't be loaded. Use [try_post_upgrade_with_migrations] to handle the error.
+pub fn post_upgrade_with_migrations<Data: 'static>(s: &'static LocalKey<RefCell<Data>>, memory_id: MemoryId, migrations: Migrations<Data>) {
+    if let Err(e) = try_post_upgrade_with_migrations(s, memory_id, migrations) {
+        trap(&e.to_string());
+    }
+}
+
+/// Same as [post_upgrade_with_migrations] but returns an error instead of trapping.
+pub fn try_post_upgrade_with_migrations<Data: 'static>(s: &'static LocalKey<RefCell<Data>>, memory_id: MemoryId, migrations: Migrations<Data>) -> Result<(), UpgradeError> {
+    
+    check_not_registered(memory_id)?;
+    
+    let (opt_header, mut payload_reader) = read_verified_payload(memory_id)?;
+    
+    // payloads without a header are from before the data versions, they are at data version 0.
+    let stored_version: u32 = opt_header.as_ref().map(|header| header.data_version).unwrap_or(0);
+    
+    let step = migrations.step(stored_version).ok_or(UpgradeError::UnknownDataVersion{ memory_id, data_version: stored_version })?;
+    
+    check_format(memory_id, &opt_header, step.format)?;
+    
+    let new_data: Data = (step.decode_and_migrate)(&mut payload_reader).map_err(|error| UpgradeError::Decode{
+        memory_id,
+        error,
+        written_by_same_type: opt_header.as_ref().map(|header| header.type_fingerprint == step.type_fingerprint),
+    })?;
+    
+    let mut migration_log: Vec<MigrationRecord> = opt_header.map(|header| header.migration_log).unwrap_or_default();
+    let timestamp_nanos: u64 = time();
+    for from_version in stored_version..migrations.latest_version() {
+        migration_log.push(MigrationRecord{
+            from_version,
+            to_version: from_version + 1,
+            timestamp_nanos,
+        });
+    }
+    
+    with_mut(s, |data| {
+        *data = new_data;
+    });
+    
+    (migrations.register_fn())(s, memory_id, migrations.latest_version(), migration_log)?;
+    pre_upgrade();
+    
+    Ok(())
+}
+
+
+type PayloadReader = Box<dyn Read>;
+
+// Reads the header of the memory-id, picks the newest payload that matches its length and sha256 in the header,
+// and returns a reader over the payload.
+fn read_verified_payload(memory_id: MemoryId) -> Result<(Option<PayloadHeader>, PayloadReader), UpgradeError> {
+    
+    let serialization_memory = get_virtual_memory(memory_id);
+    
+    let slots: Vec<(usize, PayloadHeader)> = match read_header(&serialization_memory, memory_id)? {
+        Some(slots) => slots,
+        None => {
+            // a payload from before the header.
+            let payload_length: u64 = read_stable_memory_payload_length(&serialization_memory, STABLE_MEMORY_HEADER_SIZE_BYTES)
+                .map_err(|error| UpgradeError::Conversion{ memory_id, error })?;
+            return Ok((None, Box::new(payload_reader(&serialization_memory, STABLE_MEMORY_HEADER_SIZE_BYTES, payload_length))));
+        }
+    };
+    
+    let mut opt_first_error: Option<UpgradeError> = None;
+    for (_, payload_header) in slots {
+        // an older slot is tried when the newest slot can't be read.
+        match open_payload_slot(&serialization_memory, memory_id, &payload_header) {
+            Ok(payload_reader) => {
+                return Ok((Some(payload_header), payload_reader));
+            }
+            Err(e) => {
+                opt_first_error.get_or_insert(e);
+            }
+        }
+    }
     
+    Err(opt_first_error.unwrap_or(UpgradeError::EmptyMemory{ memory_id }))
+}
+
+// Verifies the payload of the slot before deserializing it, and returns a decompressing reader over the payload.
+fn open_payload_slot(serialization_memory: &VirtualMemory<DefaultMemoryImpl>, memory_id: MemoryId, payload_header: &PayloadHeader) -> Result<PayloadReader, UpgradeError> {
+    verify_payload_slot(serialization_memory, memory_id, payload_header)?;
+    let compression: Compression = Compression::from_id(payload_header.compression_id)
+        .ok_or(UpgradeError::Decompression{ memory_id, error: format!("unknown compression id: {}", payload_header.compression_id) })?;
+    decompress_from(
+        compression, 
+        payload_reader(serialization_memory, payload_header.payload_offset, payload_header.payload_length)
+    ).map_err(|error| UpgradeError::Decompression{ memory_id, error })
+}
+
+fn verify_payload_slot(serialization_memory: &VirtualMemory<DefaultMemoryImpl>, memory_id: MemoryId, payload_header: &PayloadHeader) -> Result<(), UpgradeError> {
+    let payload_length: u64 = read_stable_memory_payload_length(serialization_memory, payload_header.payload_offset)
+        .map_err(|error| UpgradeError::Conversion{ memory_id, error })?;
+    let mut hashing_writer = HashingWriter::new(io::sink());
+    io::copy(&mut payload_reader(serialization_memory, payload_header.payload_offset, payload_length), &mut hashing_writer)
+        .map_err(|e| UpgradeError::Conversion{ memory_id, error: format!("{:?}", e) })?;
+    let (_, stored_length, checksum) = hashing_writer.finish();
+    payload_header.verify_payload(stored_length, checksum, memory_id)
+}
+
+fn payload_reader(serialization_memory: &VirtualMemory<DefaultMemoryImpl>, payload_offset: u64, payload_length: u64) -> io::BufReader<io::Take<StableReader>> {
+    io::BufReader::with_capacity(
+        STABLE_MEMORY_IO_BUFFER_SIZE, 
+        StableReader::from_virtual_memory(serialization_memory.clone(), payload_offset + 8).take(payload_length)
+    )
 }
 
+fn check_format(memory_id: MemoryId, opt_header: &Option<PayloadHeader>, expected_format: SerializationFormat) -> Result<(), UpgradeError> {
+    if let Some(header) = opt_header {
+        if header.format_id != expected_format.id() {
+            return Err(UpgradeError::FormatMismatch{ memory_id, stored_format_id: header.format_id, expected_format });
+        }
+    }
+    Ok(())
+}
 
 
 
 
 
+
+#[allow(clippy::result_unit_err)]
 pub fn locate_minimum_memory(memory: &VirtualMemory<DefaultMemoryImpl>, want_memory_size_bytes: u64) -> Result<(),()> {
     let memory_size_wasm_pages: u64 = memory.size();
     let memory_size_bytes: u64 = memory_size_wasm_pages * WASM_PAGE_SIZE_IN_BYTES as u64;
@@ -175,25 +939,56 @@ pub fn locate_minimum_memory(memory: &VirtualMemory<DefaultMemoryImpl>, want_mem
 
 
 
-fn write_data_with_length_onto_the_stable_memory(serialization_memory: &VirtualMemory<DefaultMemoryImpl>, stable_memory_offset: u64, data: &[u8]) -> Result<(), ()> {
-    locate_minimum_memory(
-        serialization_memory,
-        stable_memory_offset + 8/*len of the data*/ + data.len() as u64
-    )?; 
-    serialization_memory.write(stable_memory_offset, &((data.len() as u64).to_be_bytes()));
-    serialization_memory.write(stable_memory_offset + 8, data);
-    Ok(())
+// Serializes and compresses the data straight onto the stable memory after an 8-byte length. 
+// Returns the length and the sha256 of the written payload, 
+// or None when the payload is longer than the `opt_limit`.
+fn write_data_with_length_onto_the_stable_memory(serialization_memory: &VirtualMemory<DefaultMemoryImpl>, stable_memory_offset: u64, serialize_data_into_fn: &SerializeDataIntoFn, compression: Compression, opt_limit: Option<u64>) -> Result<Option<(u64, [u8; 32])>, String> {
+    let limit_exceeded: Rc<Cell<bool>> = Rc::new(Cell::new(false));
+    let hashing_writer = HashingWriter::new(
+        io::BufWriter::with_capacity(
+            STABLE_MEMORY_IO_BUFFER_SIZE, 
+            LimitedWriter::new(
+                StableWriter::from_virtual_memory(serialization_memory.clone(), stable_memory_offset + 8/*len of the data*/),
+                opt_limit.unwrap_or(u64::MAX),
+                limit_exceeded.clone(),
+            )
+        )
+    );
+    let write_result = compress_into(compression, hashing_writer, &mut |w| serialize_data_into_fn(w))
+        .and_then(|mut hashing_writer| {
+            hashing_writer.flush().map_err(|e| format!("{:?}", e))?;
+            Ok(hashing_writer)
+        });
+    let hashing_writer = match write_result {
+        Ok(hashing_writer) => hashing_writer,
+        Err(_) if limit_exceeded.get() => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let (_, data_len, checksum) = hashing_writer.finish();
+    serialization_memory.write(stable_memory_offset, &data_len.to_be_bytes());
+    Ok(Some((data_len, checksum)))
 }
 
-fn read_stable_memory_bytes_with_length(serialization_memory: &VirtualMemory<DefaultMemoryImpl>, stable_memory_offset: u64) -> Vec<u8> {
+fn read_stable_memory_payload_length(serialization_memory: &VirtualMemory<DefaultMemoryImpl>, stable_memory_offset: u64) -> Result<u64, String> {
+    
+    let memory_size_bytes: u64 = serialization_memory.size() * WASM_PAGE_SIZE_IN_BYTES as u64;
+    // a corrupt header can hold any offset.
+    let payload_start: u64 = stable_memory_offset.checked_add(8)
+        .ok_or(format!("the payload offset: {} is out of range", stable_memory_offset))?;
+    if memory_size_bytes < payload_start {
+        return Err(format!("the stable memory size is {} bytes, there is no payload length at offset {}", memory_size_bytes, stable_memory_offset));
+    }
     
     let mut data_len_u64_be_bytes: [u8; 8] = [0; 8];
     serialization_memory.read(stable_memory_offset, &mut data_len_u64_be_bytes);
     let data_len_u64: u64 = u64::from_be_bytes(data_len_u64_be_bytes); 
     
-    let mut data: Vec<u8> = vec![0; data_len_u64.try_into().unwrap()]; 
-    serialization_memory.read(stable_memory_offset + 8, &mut data);
-    data
+    // check the length so that a corrupt length can't make the deserialization read past the payload.
+    if data_len_u64 > memory_size_bytes - payload_start {
+        return Err(format!("the payload length: {} is bigger than the stable memory that holds it: {} bytes", data_len_u64, memory_size_bytes));
+    }
+    
+    Ok(data_len_u64)
 }
 
 
@@ -202,118 +997,1014 @@ fn read_stable_memory_bytes_with_length(serialization_memory: &VirtualMemory<Def
 
 // ---- STATE-SNAPSHOT CONTROLLER METHODS ---------
 
-fn caller_is_controller_gaurd() {
-    if is_controller(&caller()) == false {
+pub(crate) fn caller_is_controller_gaurd() {
+    if !is_controller(&caller()) {
         trap("Caller must be a controller for this method.");
     }
 }
 
-#[export_name = "canister_update controller_create_state_snapshot"]
-extern "C" fn controller_create_state_snapshot() {
-    caller_is_controller_gaurd();
-        
-    let memory_id: MemoryId = MemoryId::new(arg_data::<(u8,)>().0);
-    
-    let state_snapshot_len: u64 = with_mut(&STATE_SNAPSHOTS, |state_snapshots| {
-        match state_snapshots.get_mut(&memory_id) {
-            None => trap("no data associated with this memory_id"),
+/// The maximum length of the bytes in the reply of one download.
+/// It is below the 2 MiB reply limit of the internet-computer to leave room for the candid encoding of the reply.
+pub const MAX_REPLY_BLOB_LENGTH: u64 = 2 * 1024 * 1024 - 64 * 1024;
+
+pub(crate) fn reply_result<T: CandidType>(result: Result<T, ControllerError>) {
+    reply::<(Result<T, ControllerError>,)>((result,));
+}
+
+// MemoryId::new panics on the memory-id 255, the MemoryManager uses it to mark the unallocated buckets.
+fn controller_memory_id(memory_id: u8) -> Result<MemoryId, ControllerError> {
+    if memory_id == u8::MAX {
+        return Err(ControllerError::UnknownMemoryId{ memory_id });
+    }
+    Ok(MemoryId::new(memory_id))
+}
+
+fn with_snapshot_data<R>(memory_id: u8, f: impl FnOnce(MemoryId, &SnapshotData) -> Result<R, ControllerError>) -> Result<R, ControllerError> {
+    let memory_id_: MemoryId = controller_memory_id(memory_id)?;
+    with(&STATE_SNAPSHOTS, |state_snapshots| {
+        match state_snapshots.get(&memory_id_) {
+            None => Err(ControllerError::UnknownMemoryId{ memory_id }),
+            Some(d) => f(memory_id_, d),
+        }
+    })
+}
+
+// Drops the expired upload session of the memory-id before it calls `f`.
+fn with_snapshot_data_mut<R>(memory_id: u8, f: impl FnOnce(MemoryId, &mut SnapshotData) -> Result<R, ControllerError>) -> Result<R, ControllerError> {
+    let memory_id_: MemoryId = controller_memory_id(memory_id)?;
+    with_mut(&STATE_SNAPSHOTS, |state_snapshots| {
+        match state_snapshots.get_mut(&memory_id_) {
+            None => Err(ControllerError::UnknownMemoryId{ memory_id }),
             Some(d) => {
-                d.snapshot = Vec::new(); // clear first so don't have to hold the deserialized data and old snapshot at the same time in the heap.
-                d.snapshot = (d.serialize_data_fn)().unwrap();
-                d.snapshot.len() as u64
+                expire_upload_session(d);
+                f(memory_id_, d)
             }
         }
+    })
+}
+
+fn check_range(offset: u64, length: u64, size: u64) -> Result<(), ControllerError> {
+    if offset.checked_add(length).is_none_or(|end| end > size) {
+        return Err(ControllerError::OutOfRange{ offset, length, size });
+    }
+    Ok(())
+}
+
+fn check_reply_length(length: u64) -> Result<(), ControllerError> {
+    if length > MAX_REPLY_BLOB_LENGTH {
+        return Err(ControllerError::ReplyTooLarge{ length, max_length: MAX_REPLY_BLOB_LENGTH });
+    }
+    Ok(())
+}
+
+// The length of the chunk at the offset, clamped to the max_length, to the bytes that fit in one reply, and to the end.
+fn download_chunk_length(offset: u64, max_length: u64, total_length: u64) -> Result<u64, ControllerError> {
+    if offset > total_length {
+        return Err(ControllerError::OutOfRange{ offset, length: 0, size: total_length });
+    }
+    Ok(max_length.min(MAX_REPLY_BLOB_LENGTH).min(total_length - offset))
+}
+
+fn controller_check_label(label: &str) -> Result<(), ControllerError> {
+    check_label(label).map_err(|_| ControllerError::LabelTooLong{ max_length: NAMED_SNAPSHOT_LABEL_MAX_LENGTH as u64 })
+}
+
+// Serializes the global variable and compresses it with the memory-id's compression.
+fn serialize_compressed(d: &SnapshotData) -> Result<Vec<u8>, ControllerError> {
+    let b: Vec<u8> = (d.serialize_data_fn)().map_err(ControllerError::Serialization)?;
+    compress_bytes(d.compression, &b).map_err(ControllerError::Compression)
+}
+
+// Serializes the data into the snapshot, compressed with the memory-id's compression when `compress` is true.
+// Returns the length and the sha256 of the snapshot.
+fn create_state_snapshot(d: &mut SnapshotData, compress: bool) -> Result<(u64, [u8; 32]), ControllerError> {
+    check_no_upload_session(d)?;
+    let current_generation: u64 = generation(d.localkey_address).generation;
+    if d.dirty_tracking && d.snapshot_generation == Some((current_generation, compress)) {
+        return Ok((d.snapshot.len() as u64, d.snapshot_hasher.clone().finalize().into()));
+    }
+    d.clear_snapshot(Compression::None); // clear first so don't have to hold the deserialized data and old snapshot at the same time in the heap.
+    d.snapshot = (d.serialize_data_fn)().map_err(ControllerError::Serialization)?;
+    if compress && d.compression != Compression::None {
+        d.snapshot = compress_bytes(d.compression, &d.snapshot).map_err(ControllerError::Compression)?;
+        d.snapshot_compression = d.compression;
+    }
+    d.snapshot_generation = Some((current_generation, compress));
+    d.snapshot_hasher.update(&d.snapshot);
+    d.snapshot_timestamp_nanos = Some(time());
+    d.snapshot_source = Some(StateSnapshotSource::Serialization);
+    Ok((d.snapshot.len() as u64, d.snapshot_hasher.clone().finalize().into()))
+}
+
+#[export_name = "canister_update controller_create_state_snapshot"]
+extern "C" fn controller_create_state_snapshot() {
+    caller_is_controller_gaurd();
+
+    let (memory_id,) = arg_data::<(u8,)>();
+
+    reply_result::<(u64, ByteBuf)>(with_snapshot_data_mut(memory_id, |_, d| {
+        let (state_snapshot_len, state_snapshot_sha256) = create_state_snapshot(d, true)?;
+        Ok((state_snapshot_len, ByteBuf::from(state_snapshot_sha256.to_vec())))
+    }));
+}
+
+#[export_name = "canister_update controller_create_raw_state_snapshot"]
+extern "C" fn controller_create_raw_state_snapshot() {
+    caller_is_controller_gaurd();
+
+    let (memory_id,) = arg_data::<(u8,)>();
+
+    reply_result::<(u64, ByteBuf)>(with_snapshot_data_mut(memory_id, |_, d| {
+        let (state_snapshot_len, state_snapshot_sha256) = create_state_snapshot(d, false)?;
+        Ok((state_snapshot_len, ByteBuf::from(state_snapshot_sha256.to_vec())))
+    }));
+}
+
+#[export_name = "canister_query controller_state_snapshot_compression"]
+extern "C" fn controller_state_snapshot_compression() {
+    caller_is_controller_gaurd();
+
+    let (memory_id,) = arg_data::<(u8,)>();
+
+    reply_result::<Compression>(with_snapshot_data(memory_id, |_, d| Ok(d.snapshot_compression)));
+}
+
+
+#[export_name = "canister_query controller_state_snapshot_sha256"]
+extern "C" fn controller_state_snapshot_sha256() {
+    caller_is_controller_gaurd();
+
+    let (memory_id,) = arg_data::<(u8,)>();
+
+    reply_result::<ByteBuf>(with_snapshot_data(memory_id, |_, d| Ok(ByteBuf::from(d.snapshot_sha256().to_vec()))));
+}
+
+#[export_name = "canister_query controller_state_snapshot_format"]
+extern "C" fn controller_state_snapshot_format() {
+    caller_is_controller_gaurd();
+
+    let (memory_id,) = arg_data::<(u8,)>();
+
+    reply_result::<SerializationFormat>(with_snapshot_data(memory_id, |_, d| Ok(d.format)));
+}
+
+
+
+/// The modification generation of a global variable, returned by the `controller_state_generation` canister method.
+/// The generation counts the modifications since the global variable was registered in this canister version, 
+/// see [set_dirty_tracking].
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StateGeneration {
+    pub generation: u64,
+    pub last_modified_timestamp_nanos: Option<u64>,
+}
+
+/// The current snapshot of a registered memory-id, returned by the `controller_list_state_snapshots` canister method.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StateSnapshotInfo {
+    pub memory_id: u8,
+    /// The rust type name of the registered global variable.
+    pub type_name: String,
+    pub snapshot_length: u64,
+    /// The sha256 of the snapshot bytes.
+    pub snapshot_sha256: ByteBuf,
+    pub snapshot_compression: Compression,
+    /// The time of the creation, or of the last upload of the snapshot. 
+    pub snapshot_timestamp_nanos: Option<u64>,
+    /// `None` when there is no snapshot.
+    pub snapshot_source: Option<StateSnapshotSource>,
+}
+
+fn list_state_snapshots() -> Vec<StateSnapshotInfo> {
+    with(&STATE_SNAPSHOTS, |state_snapshots| {
+        state_snapshots.values().map(|d| {
+            StateSnapshotInfo {
+                memory_id: d.memory_id,
+                type_name: d.type_name.to_string(),
+                snapshot_length: d.snapshot_length(),
+                snapshot_sha256: ByteBuf::from(d.snapshot_sha256().to_vec()),
+                snapshot_compression: d.snapshot_compression,
+                snapshot_timestamp_nanos: d.snapshot_timestamp_nanos,
+                snapshot_source: d.snapshot_source,
+            }
+        }).collect()
+    })
+}
+
+#[export_name = "canister_query controller_list_state_snapshots"]
+extern "C" fn controller_list_state_snapshots() {
+    caller_is_controller_gaurd();
+
+    reply_result::<Vec<StateSnapshotInfo>>(Ok(list_state_snapshots()));
+}
+
+#[export_name = "canister_query controller_state_generation"]
+extern "C" fn controller_state_generation() {
+    caller_is_controller_gaurd();
+
+    let (memory_id,) = arg_data::<(u8,)>();
+
+    reply_result::<StateGeneration>(with_snapshot_data(memory_id, |_, d| {
+        let g = generation(d.localkey_address);
+        Ok(StateGeneration{ generation: g.generation, last_modified_timestamp_nanos: g.last_modified_timestamp_nanos })
+    }));
+}
+
+
+/// The maximum number of instructions of an upgrade, the pre_upgrade hook and the post_upgrade hook share this limit.
+pub const UPGRADE_INSTRUCTION_LIMIT: u64 = 300_000_000_000;
+
+/// The report of the `controller_check_upgrade_readiness` canister method.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct UpgradeReadiness {
+    pub memory_ids: Vec<MemoryIdUpgradeReadiness>,
+    pub total_instructions: u64,
+    pub upgrade_instruction_limit: u64,
+    pub total_stable_memory_growth_wasm_pages: u64,
+    /// `true` when each memory-id serializes without an error and the total instructions are within the limit.
+    pub ready: bool,
+}
+
+/// The report of one memory-id in the [UpgradeReadiness].
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct MemoryIdUpgradeReadiness {
+    pub memory_id: u8,
+    /// The length of the payload after the compression.
+    pub payload_length: u64,
+    pub instructions: u64,
+    /// The wasm pages that the memory-id's stable memory grows by to hold the payload next to the last payload.
+    pub stable_memory_growth_wasm_pages: u64,
+    /// `false` when the pre_upgrade skips the memory-id because of the [dirty tracking](set_dirty_tracking).
+    pub modified: bool,
+    pub error: Option<String>,
+}
+
+// Serializes each registered global variable the same way as the pre_upgrade, without writing it.
+fn check_upgrade_readiness() -> UpgradeReadiness {
+    let memory_ids: Vec<MemoryIdUpgradeReadiness> = with(&STATE_SNAPSHOTS, |state_snapshots| {
+        state_snapshots.iter().map(|(memory_id, d)| {
+            let start_instructions: u64 = instruction_counter();
+            let serialization_result: Result<u64, String> = compress_into(
+                d.compression, 
+                HashingWriter::new(io::sink()), 
+                &mut |w| (d.serialize_data_into_fn)(w)
+            ).map(|hashing_writer| hashing_writer.finish().1);
+            let instructions: u64 = instruction_counter().saturating_sub(start_instructions);
+            let (payload_length, error) = match serialization_result {
+                Ok(payload_length) => (payload_length, None),
+                Err(e) => (0, Some(e)),
+            };
+            
+            let serialization_memory = get_virtual_memory(*memory_id);
+            let payload_offset: u64 = next_payload_placement(&serialization_memory, *memory_id).regions.into_iter()
+                .find(|(_, opt_limit)| opt_limit.is_none_or(|limit| payload_length <= limit))
+                .map(|(payload_offset, _)| payload_offset)
+                .unwrap_or(STABLE_MEMORY_HEADER_SIZE_BYTES);
+            let want_pages: u64 = (payload_offset + 8 + payload_length).div_ceil(WASM_PAGE_SIZE_IN_BYTES as u64);
+            
+            MemoryIdUpgradeReadiness {
+                memory_id: d.memory_id,
+                payload_length,
+                instructions,
+                stable_memory_growth_wasm_pages: want_pages.saturating_sub(serialization_memory.size()),
+                modified: !(d.dirty_tracking && d.upgrade_payload_generation == Some(generation(d.localkey_address).generation)),
+                error,
+            }
+        }).collect()
     });
+    let total_instructions: u64 = memory_ids.iter().filter(|m| m.modified).map(|m| m.instructions).sum();
+    UpgradeReadiness {
+        ready: memory_ids.iter().all(|m| m.error.is_none()) && total_instructions <= UPGRADE_INSTRUCTION_LIMIT,
+        total_stable_memory_growth_wasm_pages: memory_ids.iter().filter(|m| m.modified).map(|m| m.stable_memory_growth_wasm_pages).sum(),
+        total_instructions,
+        upgrade_instruction_limit: UPGRADE_INSTRUCTION_LIMIT,
+        memory_ids,
+    }
+}
+
+#[export_name = "canister_update controller_check_upgrade_readiness"]
+extern "C" fn controller_check_upgrade_readiness() {
+    caller_is_controller_gaurd();
+    
+    reply_result::<UpgradeReadiness>(Ok(check_upgrade_readiness()));
+}
+
+
+/// The report of the `controller_verify_upgrade_payload` canister method.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct UpgradePayloadVerification {
+    /// The length of the stored payload, after the compression.
+    pub payload_length: u64,
+    /// The time that the payload was written, `None` for a payload without a header.
+    pub timestamp_nanos: Option<u64>,
+    /// `None` when the payload decodes as the registered type.
+    pub error: Option<String>,
+}
+
+// Decodes the payload that the next post_upgrade reads, as the type that is registered with the memory-id.
+fn verify_upgrade_payload(memory_id: MemoryId, d: &SnapshotData) -> Result<UpgradePayloadVerification, UpgradeError> {
+    let (opt_header, mut payload_reader) = read_verified_payload(memory_id)?;
+    let payload_length: u64 = match opt_header {
+        Some(ref header) => header.payload_length,
+        None => read_stable_memory_payload_length(&get_virtual_memory(memory_id), STABLE_MEMORY_HEADER_SIZE_BYTES)
+            .map_err(|error| UpgradeError::Conversion{ memory_id, error })?,
+    };
+    let error: Option<String> = decode_upgrade_payload(memory_id, d, &opt_header, &mut payload_reader).err().map(|e| e.to_string());
+    Ok(UpgradePayloadVerification{
+        payload_length,
+        timestamp_nanos: opt_header.map(|header| header.timestamp_nanos),
+        error,
+    })
+}
+
+fn decode_upgrade_payload(memory_id: MemoryId, d: &SnapshotData, opt_header: &Option<PayloadHeader>, payload_reader: &mut PayloadReader) -> Result<(), UpgradeError> {
+    if let Some(header) = opt_header {
+        if header.data_version != d.data_version {
+            return Err(UpgradeError::UnknownDataVersion{ memory_id, data_version: header.data_version });
+        }
+    }
+    check_format(memory_id, opt_header, d.format)?;
+    (d.decode_data_fn)(payload_reader).map(|_| ()).map_err(|error| UpgradeError::Decode{
+        memory_id,
+        error,
+        written_by_same_type: opt_header.as_ref().map(|header| header.type_fingerprint == d.type_fingerprint),
+    })
+}
+
+#[export_name = "canister_query controller_verify_upgrade_payload"]
+extern "C" fn controller_verify_upgrade_payload() {
+    caller_is_controller_gaurd();
+
+    let (memory_id,) = arg_data::<(u8,)>();
 
-    reply::<(u64,)>((state_snapshot_len,));
+    reply_result::<UpgradePayloadVerification>(with_snapshot_data(memory_id, |memory_id, d| {
+        Ok(verify_upgrade_payload(memory_id, d)
+            .unwrap_or_else(|e| UpgradePayloadVerification{ payload_length: 0, timestamp_nanos: None, error: Some(e.to_string()) }))
+    }));
 }
 
 
 #[export_name = "canister_query controller_download_state_snapshot"]
 extern "C" fn controller_download_state_snapshot() {
     caller_is_controller_gaurd();
-    
+
     let (memory_id, offset, length) = arg_data::<(u8, u64, u64)>();
-        
-    with(&STATE_SNAPSHOTS, |state_snapshots| {
-        match state_snapshots.get(&MemoryId::new(memory_id)) {
-            None => trap("no data associated with this memory_id"),
-            Some(d) => {
-                reply::<(&Bytes/*&[u8]*/,)>(( Bytes::new(&(d.snapshot[(offset as usize)..((offset + length) as usize)])), ));
-            }
-        }
-    });
+
+    with_snapshot_data(memory_id, |_, d| {
+        check_reply_length(length)?;
+        check_range(offset, length, d.snapshot.len() as u64)?;
+        reply_result::<&Bytes/*&[u8]*/>(Ok(Bytes::new(&d.snapshot[(offset as usize)..((offset + length) as usize)])));
+        Ok(())
+    }).unwrap_or_else(|e| reply_result::<&Bytes>(Err(e)));
+}
+
+/// A chunk of a download, returned by the `controller_download_state_snapshot_chunk` and 
+/// `controller_download_named_state_snapshot_chunk` canister methods.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DownloadChunk {
+    pub bytes: ByteBuf,
+    /// The length of the whole snapshot.
+    pub total_length: u64,
+    /// The offset of the next chunk, the download is complete when it is equal to the `total_length`.
+    pub next_offset: u64,
+}
+
+#[export_name = "canister_query controller_download_state_snapshot_chunk"]
+extern "C" fn controller_download_state_snapshot_chunk() {
+    caller_is_controller_gaurd();
+
+    let (memory_id, offset, max_length) = arg_data::<(u8, u64, u64)>();
+
+    reply_result::<DownloadChunk>(with_snapshot_data(memory_id, |_, d| {
+        let total_length: u64 = d.snapshot.len() as u64;
+        let length: u64 = download_chunk_length(offset, max_length, total_length)?;
+        Ok(DownloadChunk{
+            bytes: ByteBuf::from(&d.snapshot[(offset as usize)..((offset + length) as usize)]),
+            total_length,
+            next_offset: offset + length,
+        })
+    }));
 }
 
 #[export_name = "canister_update controller_clear_state_snapshot"]
 extern "C" fn controller_clear_state_snapshot() {
     caller_is_controller_gaurd();
-    
-    let memory_id: MemoryId = MemoryId::new(arg_data::<(u8,)>().0);
-    
-    with_mut(&STATE_SNAPSHOTS, |state_snapshots| {
-        match state_snapshots.get_mut(&memory_id) {
-            None => trap("no data associated with this memory_id"),
-            Some(d) => {
-                d.snapshot = Vec::new();
-            }
+
+    let (memory_id,) = arg_data::<(u8,)>();
+
+    reply_result::<()>(with_snapshot_data_mut(memory_id, |_, d| {
+        check_no_upload_session(d)?;
+        d.clear_snapshot(Compression::None);
+        Ok(())
+    }));
+}
+
+#[export_name = "canister_update controller_clear_state_snapshot_with_compression"]
+extern "C" fn controller_clear_state_snapshot_with_compression() {
+    caller_is_controller_gaurd();
+
+    let (memory_id, compression) = arg_data::<(u8, Compression)>();
+
+    reply_result::<()>(with_snapshot_data_mut(memory_id, |_, d| {
+        check_no_upload_session(d)?;
+        if !compression.is_enabled() {
+            return Err(ControllerError::CompressionNotEnabled{ compression });
         }
-    });
-    
-    reply::<()>(());
+        d.clear_snapshot(compression);
+        Ok(())
+    }));
 }
 
 #[export_name = "canister_update controller_append_state_snapshot"]
 extern "C" fn controller_append_state_snapshot() {
     caller_is_controller_gaurd();
-    
+
     let (memory_id, mut bytes) = arg_data::<(u8, ByteBuf)>();
-    
+
+    reply_result::<()>(with_snapshot_data_mut(memory_id, |_, d| {
+        check_no_upload_session(d)?;
+        d.snapshot_hasher.update(&bytes);
+        d.snapshot.append(&mut bytes);
+        d.snapshot_generation = None;
+        d.snapshot_timestamp_nanos = Some(time());
+        d.snapshot_source = Some(StateSnapshotSource::Upload);
+        d.upload = None;
+        Ok(())
+    }));
+}
+
+#[export_name = "canister_update controller_put_state_snapshot_chunk"]
+extern "C" fn controller_put_state_snapshot_chunk() {
+    caller_is_controller_gaurd();
+
+    let (memory_id, chunk_index, chunk_count, bytes) = arg_data::<(u8, u32, u32, ByteBuf)>();
+
+    reply_result::<()>(with_snapshot_data_mut(memory_id, |_, d| {
+        check_no_upload_session(d)?;
+        if chunk_index >= chunk_count {
+            return Err(ControllerError::ChunkIndexOutOfRange{ chunk_index, chunk_count });
+        }
+        if d.upload.is_none() {
+            // the first chunk of an upload clears the snapshot, and keeps the compression of the last clear.
+            d.clear_snapshot(d.snapshot_compression);
+            d.upload = Some(ChunkedUpload::new(chunk_count));
+        }
+        let upload: &mut ChunkedUpload = d.upload.as_mut().unwrap();
+        if upload.is_complete() {
+            return upload.put_chunk(chunk_index, chunk_count, bytes.into_vec());
+        }
+        upload.put_chunk(chunk_index, chunk_count, bytes.into_vec())?;
+        if upload.is_complete() {
+            d.snapshot = upload.take_joined_chunks();
+            d.snapshot_hasher.update(&d.snapshot);
+        }
+        d.snapshot_timestamp_nanos = Some(time());
+        d.snapshot_source = Some(StateSnapshotSource::Upload);
+        Ok(())
+    }));
+}
+
+/// The progress of an upload with the `controller_put_state_snapshot_chunk` canister method, 
+/// returned by the `controller_state_snapshot_upload_status` canister method.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StateSnapshotUploadStatus {
+    pub chunk_count: u32,
+    pub received_chunks: u32,
+    /// The indexes of the chunks that did not arrive yet, the snapshot can be loaded when it is empty.
+    pub missing_chunks: Vec<u32>,
+}
+
+#[export_name = "canister_query controller_state_snapshot_upload_status"]
+extern "C" fn controller_state_snapshot_upload_status() {
+    caller_is_controller_gaurd();
+
+    let (memory_id,) = arg_data::<(u8,)>();
+
+    reply_result::<Option<StateSnapshotUploadStatus>>(with_snapshot_data(memory_id, |_, d| {
+        Ok(d.upload.as_ref().map(|upload| StateSnapshotUploadStatus{
+            chunk_count: upload.chunk_count(),
+            received_chunks: upload.received_chunks(),
+            missing_chunks: upload.missing_chunks(),
+        }))
+    }));
+}
+
+// The snapshot of an upload with controller_put_state_snapshot_chunk can be loaded once all of the chunks arrive.
+fn check_upload_complete(d: &SnapshotData) -> Result<(), ControllerError> {
+    match d.upload {
+        Some(ref upload) if !upload.is_complete() => Err(ControllerError::IncompleteUpload{ 
+            received_chunks: upload.received_chunks(), 
+            chunk_count: upload.chunk_count(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+// Ends the upload session, frees the staged bytes of a staged session.
+fn end_upload_session(d: &mut SnapshotData) {
+    if d.upload_session.take().is_some_and(|upload_session| upload_session.staged) {
+        clear_staged(d.memory_id);
+    }
+}
+
+// Drops the upload session and frees the snapshot buffer and the staged bytes when the session is expired.
+fn expire_upload_session(d: &mut SnapshotData) {
+    if d.upload_session.as_ref().is_some_and(|upload_session| upload_session.is_expired(time())) {
+        end_upload_session(d);
+        d.clear_snapshot(Compression::None);
+    }
+}
+
+// An upload session holds the snapshot buffer, the other methods that change the snapshot wait for the session to end.
+fn check_no_upload_session(d: &SnapshotData) -> Result<(), ControllerError> {
+    match d.upload_session {
+        Some(ref upload_session) => Err(ControllerError::UploadSessionInProgress{
+            session_id: upload_session.session_id,
+            expires_at_nanos: upload_session.expires_at_nanos,
+        }),
+        None => Ok(()),
+    }
+}
+
+// The snapshot of an upload session can be loaded once all of the bytes arrive and their sha256 matches the declared sha256.
+fn check_upload_session_complete(d: &SnapshotData) -> Result<(), ControllerError> {
+    if let Some(ref upload_session) = d.upload_session {
+        if !upload_session.is_complete() {
+            return Err(ControllerError::IncompleteUploadSession{
+                received_length: upload_session.received_length(),
+                total_length: upload_session.total_length,
+            });
+        }
+        if upload_session.received_sha256 != Some(upload_session.sha256) {
+            return Err(ControllerError::UploadChecksumMismatch{ session_id: upload_session.session_id });
+        }
+    }
+    Ok(())
+}
+
+fn with_upload_session_data_mut<R>(session_id: u64, f: impl FnOnce(&mut SnapshotData) -> Result<R, ControllerError>) -> Result<R, ControllerError> {
     with_mut(&STATE_SNAPSHOTS, |state_snapshots| {
-        match state_snapshots.get_mut(&MemoryId::new(memory_id)) {
-            None => trap("no data associated with this memory_id"),
-            Some(d) => {
-                d.snapshot.append(&mut bytes);
+        let d: &mut SnapshotData = state_snapshots.values_mut()
+            .find(|d| d.upload_session.as_ref().is_some_and(|upload_session| upload_session.session_id == session_id))
+            .ok_or(ControllerError::UnknownUploadSession{ session_id })?;
+        expire_upload_session(d);
+        if d.upload_session.is_none() {
+            return Err(ControllerError::UnknownUploadSession{ session_id });
+        }
+        f(d)
+    })
+}
+
+// Drops the upload session when it expires, without waiting for a controller method to use its memory-id.
+#[cfg(feature = "timers")]
+fn schedule_upload_session_expiry(session_id: u64, expires_at_nanos: u64) {
+    ic_cdk_timers::set_timer(std::time::Duration::from_nanos(expires_at_nanos.saturating_sub(time())), move || {
+        let _ = with_upload_session_data_mut(session_id, |d| {
+            // the session is extended by each chunk.
+            if let Some(ref upload_session) = d.upload_session {
+                schedule_upload_session_expiry(session_id, upload_session.expires_at_nanos);
+            }
+            Ok(())
+        });
+    });
+}
+
+// Continues the staged upload session of the memory-id after an upgrade.
+fn restore_staged_upload_session(d: &mut SnapshotData) {
+    if let Some((compression, upload_session)) = get_staged_session(d.memory_id) {
+        d.snapshot_compression = compression;
+        d.snapshot_source = Some(StateSnapshotSource::Upload);
+        #[cfg(feature = "timers")]
+        schedule_upload_session_expiry(upload_session.session_id, upload_session.expires_at_nanos);
+        d.upload_session = Some(upload_session);
+    }
+}
+
+#[export_name = "canister_update controller_begin_state_snapshot_upload"]
+extern "C" fn controller_begin_state_snapshot_upload() {
+    caller_is_controller_gaurd();
+
+    let (memory_id, total_length, sha256, compression) = arg_data::<(u8, u64, ByteBuf, Compression)>();
+
+    reply_result::<u64>(with_snapshot_data_mut(memory_id, |_, d| {
+        check_no_upload_session(d)?;
+        if !compression.is_enabled() {
+            return Err(ControllerError::CompressionNotEnabled{ compression });
+        }
+        let sha256: [u8; 32] = sha256.as_slice().try_into().map_err(|_| ControllerError::InvalidSha256{ length: sha256.len() as u64 })?;
+        d.clear_snapshot(compression);
+        let upload_session = UploadSession::new(total_length, sha256, d.upload_staging, time());
+        let session_id: u64 = upload_session.session_id;
+        if upload_session.staged {
+            clear_staged(d.memory_id);
+            store_staged_session(d.memory_id, compression, &upload_session);
+        }
+        #[cfg(feature = "timers")]
+        schedule_upload_session_expiry(session_id, upload_session.expires_at_nanos);
+        d.upload_session = Some(upload_session);
+        d.snapshot_timestamp_nanos = Some(time());
+        d.snapshot_source = Some(StateSnapshotSource::Upload);
+        Ok(session_id)
+    }));
+}
+
+#[export_name = "canister_update controller_upload_state_snapshot_chunk"]
+extern "C" fn controller_upload_state_snapshot_chunk() {
+    caller_is_controller_gaurd();
+
+    let (session_id, offset, bytes) = arg_data::<(u64, u64, ByteBuf)>();
+
+    reply_result::<()>(with_upload_session_data_mut(session_id, |d| {
+        let upload_session: &mut UploadSession = d.upload_session.as_mut().unwrap();
+        check_range(offset, bytes.len() as u64, upload_session.total_length)?;
+        let end: u64 = offset + bytes.len() as u64;
+        if upload_session.staged {
+            write_staged(d.memory_id, offset, &bytes);
+        } else {
+            if (d.snapshot.len() as u64) < end {
+                d.snapshot.resize(end as usize, 0);
+            }
+            d.snapshot[(offset as usize)..(end as usize)].copy_from_slice(&bytes);
+        }
+        upload_session.receive(offset, end);
+        upload_session.touch(time());
+        if upload_session.is_complete() {
+            d.snapshot_hasher = Sha256::new();
+            if upload_session.staged {
+                let mut hashing_writer = HashingWriter::new(io::sink());
+                io::copy(&mut StagedReader::new(d.memory_id, upload_session.total_length), &mut hashing_writer)
+                    .map_err(|e| ControllerError::Decode(format!("error reading the staged bytes: {:?}", e)))?;
+                upload_session.received_sha256 = Some(hashing_writer.finish().2);
+            } else {
+                d.snapshot_hasher.update(&d.snapshot);
+                upload_session.received_sha256 = Some(d.snapshot_hasher.clone().finalize().into());
             }
         }
+        if upload_session.staged {
+            store_staged_session(d.memory_id, d.snapshot_compression, upload_session);
+        }
+        d.snapshot_generation = None;
+        d.snapshot_timestamp_nanos = Some(time());
+        Ok(())
+    }));
+}
+
+#[export_name = "canister_update controller_cancel_state_snapshot_upload"]
+extern "C" fn controller_cancel_state_snapshot_upload() {
+    caller_is_controller_gaurd();
+
+    let (session_id,) = arg_data::<(u64,)>();
+
+    reply_result::<()>(with_upload_session_data_mut(session_id, |d| {
+        end_upload_session(d);
+        d.clear_snapshot(Compression::None);
+        Ok(())
+    }));
+}
+
+/// An upload session, returned by the `controller_state_snapshot_upload_session` canister method.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct UploadSessionStatus {
+    pub session_id: u64,
+    pub memory_id: u8,
+    pub total_length: u64,
+    pub received_length: u64,
+    /// The ranges of the bytes that did not arrive yet.
+    pub missing_ranges: Vec<ByteRange>,
+    /// The session is extended by each chunk.
+    pub expires_at_nanos: u64,
+}
+
+#[export_name = "canister_query controller_state_snapshot_upload_session"]
+extern "C" fn controller_state_snapshot_upload_session() {
+    caller_is_controller_gaurd();
+
+    let (session_id,) = arg_data::<(u64,)>();
+
+    with(&STATE_SNAPSHOTS, |state_snapshots| {
+        reply_result::<UploadSessionStatus>(
+            state_snapshots.values()
+                .find_map(|d| {
+                    d.upload_session.as_ref()
+                        .filter(|upload_session| upload_session.session_id == session_id && !upload_session.is_expired(time()))
+                        .map(|upload_session| UploadSessionStatus{
+                            session_id,
+                            memory_id: d.memory_id,
+                            total_length: upload_session.total_length,
+                            received_length: upload_session.received_length(),
+                            missing_ranges: upload_session.missing_ranges(),
+                            expires_at_nanos: upload_session.expires_at_nanos,
+                        })
+                })
+                .ok_or(ControllerError::UnknownUploadSession{ session_id })
+        );
     });
-    
-    reply::<()>(());
+}
+
+/// The report of the `controller_validate_state_snapshot` canister method.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StateSnapshotValidation {
+    /// The length of the snapshot bytes, before the decompression.
+    pub snapshot_length: u64,
+    /// The length of the serialized data, after the decompression.
+    pub decoded_length: u64,
+    /// `None` when the snapshot decodes as the registered type.
+    pub error: Option<String>,
+    /// The invariant checks that the decoded data does not pass.
+    pub failed_checks: Vec<String>,
+}
+
+// Decompresses and decodes the snapshot bytes as the registered type.
+// Returns the decoded data, or the error, and the length of the serialized data after the decompression.
+fn decode_snapshot_bytes(d: &SnapshotData, snapshot_compression: Compression, snapshot: &[u8]) -> (Result<Box<dyn Any>, ControllerError>, u64) {
+    let decompressed: Vec<u8>;
+    let b: &[u8] = match snapshot_compression {
+        Compression::None => snapshot,
+        compression => match decompress_bytes(compression, snapshot) {
+            Ok(v) => {
+                decompressed = v;
+                &decompressed
+            }
+            Err(e) => return (Err(ControllerError::Compression(e)), 0),
+        }
+    };
+    ((d.decode_bytes_fn)(b).map_err(ControllerError::Decode), b.len() as u64)
+}
+
+// Decodes the snapshot of the memory-id, through a reader from the staging area when the upload session is staged.
+fn decode_snapshot(d: &SnapshotData) -> (Result<Box<dyn Any>, ControllerError>, u64) {
+    match d.staged_upload_session() {
+        Some(upload_session) => {
+            let staged_reader = StagedReader::new(d.memory_id, upload_session.total_length);
+            let mut reader = match decompress_from(d.snapshot_compression, staged_reader) {
+                Ok(r) => CountingReader::new(r),
+                Err(e) => return (Err(ControllerError::Compression(e)), 0),
+            };
+            let result = (d.decode_data_fn)(&mut reader).map_err(ControllerError::Decode);
+            (result, reader.length())
+        }
+        None => decode_snapshot_bytes(d, d.snapshot_compression, &d.snapshot),
+    }
+}
+
+// Decompresses and decodes the snapshot as the registered type, without loading it onto the global variable.
+fn validate_state_snapshot(d: &SnapshotData) -> StateSnapshotValidation {
+    let mut validation = StateSnapshotValidation{
+        snapshot_length: d.snapshot_length(),
+        decoded_length: 0,
+        error: None,
+        failed_checks: Vec::new(),
+    };
+    let (result, decoded_length) = decode_snapshot(d);
+    validation.decoded_length = decoded_length;
+    match result {
+        Ok(data) => {
+            validation.failed_checks = failed_checks(&d.invariants, &*data);
+        }
+        Err(e) => {
+            validation.error = Some(e.to_string());
+        }
+    }
+    validation
+}
+
+// An update method for the higher instruction limit, it does not change the state.
+#[export_name = "canister_update controller_validate_state_snapshot"]
+extern "C" fn controller_validate_state_snapshot() {
+    caller_is_controller_gaurd();
+
+    let (memory_id,) = arg_data::<(u8,)>();
+
+    reply_result::<StateSnapshotValidation>(with_snapshot_data(memory_id, |_, d| {
+        check_upload_complete(d)?;
+        check_upload_session_complete(d)?;
+        Ok(validate_state_snapshot(d))
+    }));
 }
 
 #[export_name = "canister_update controller_load_state_snapshot"]
 extern "C" fn controller_load_state_snapshot() {
     caller_is_controller_gaurd();
-    
-    let memory_id: MemoryId = MemoryId::new(arg_data::<(u8,)>().0);
-    
-    with(&STATE_SNAPSHOTS, |state_snapshots| {
-        match state_snapshots.get(&memory_id) {
-            None => trap("no data associated with this memory_id"),
-            Some(d) => {
-                (d.load_data_fn)(&d.snapshot).unwrap();
-            }
+
+    let (memory_id,) = arg_data::<(u8,)>();
+
+    reply_result::<()>(with_snapshot_data_mut(memory_id, |_, d| {
+        check_upload_complete(d)?;
+        check_upload_session_complete(d)?;
+        let new_data: Box<dyn Any> = decode_snapshot(d).0?;
+        load_state_snapshot(d, new_data)?;
+        // the session ends with the load, the snapshot in the heap stays.
+        // the bytes of a staged session are freed with the session, so there is no snapshot left.
+        let staged: bool = d.staged_upload_session().is_some();
+        end_upload_session(d);
+        if staged {
+            d.clear_snapshot(Compression::None);
         }
-    });
-    
-    reply::<()>(());
+        Ok(())
+    }));
+}
+
+// Runs the invariant checks on the decoded snapshot, stores a rollback point, and then loads the snapshot onto the global variable.
+// The global variable and the rollback points are not changed when it returns an error.
+fn load_state_snapshot(d: &SnapshotData, new_data: Box<dyn Any>) -> Result<(), ControllerError> {
+    let failed_checks: Vec<String> = failed_checks(&d.invariants, &*new_data);
+    if !failed_checks.is_empty() {
+        return Err(ControllerError::InvariantChecks(failed_checks));
+    }
+    if d.rollback_limit > 0 {
+        // the rollback point is serialized before the load so that the current state can be restored.
+        let bytes: Vec<u8> = serialize_compressed(d)?;
+        push_rollback_point(d.memory_id, d.rollback_limit, RollbackPoint{ compression: d.compression, timestamp_nanos: time(), bytes });
+    }
+    (d.set_data_fn)(new_data);
+    Ok(())
+}
+
+/// A named state-snapshot, returned by the `controller_create_named_state_snapshot` and 
+/// `controller_list_named_state_snapshots` canister methods.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct NamedStateSnapshot {
+    pub label: String,
+    pub timestamp_nanos: u64,
+    /// The length of the snapshot bytes, after the compression.
+    pub length: u64,
+    /// The sha256 of the snapshot bytes, after the compression.
+    pub sha256: ByteBuf,
+    pub compression: Compression,
+}
+
+impl NamedStateSnapshot {
+    fn new(label: String, info: NamedSnapshotInfo) -> Self {
+        Self {
+            label,
+            timestamp_nanos: info.timestamp_nanos,
+            length: info.length,
+            sha256: ByteBuf::from(info.sha256.to_vec()),
+            compression: info.compression,
+        }
+    }
+}
+
+#[export_name = "canister_update controller_create_named_state_snapshot"]
+extern "C" fn controller_create_named_state_snapshot() {
+    caller_is_controller_gaurd();
+
+    let (memory_id, label) = arg_data::<(u8, String)>();
+
+    reply_result::<NamedStateSnapshot>(with_snapshot_data(memory_id, |_, d| {
+        controller_check_label(&label)?;
+        let bytes: Vec<u8> = serialize_compressed(d)?;
+        let info = NamedSnapshotInfo{
+            compression: d.compression,
+            timestamp_nanos: time(),
+            length: bytes.len() as u64,
+            sha256: sha256(&bytes),
+        };
+        let named_state_snapshot = NamedStateSnapshot::new(label.clone(), info.clone());
+        insert_named_snapshot(d.memory_id, &label, info, &bytes).map_err(|_| ControllerError::LabelTooLong{ max_length: NAMED_SNAPSHOT_LABEL_MAX_LENGTH as u64 })?;
+        Ok(named_state_snapshot)
+    }));
+}
+
+#[export_name = "canister_query controller_list_named_state_snapshots"]
+extern "C" fn controller_list_named_state_snapshots() {
+    caller_is_controller_gaurd();
+
+    let (memory_id,) = arg_data::<(u8,)>();
+
+    reply_result::<Vec<NamedStateSnapshot>>(controller_memory_id(memory_id).map(|_| {
+        list_named_snapshots(memory_id).into_iter().map(|(label, info)| NamedStateSnapshot::new(label, info)).collect()
+    }));
+}
+
+#[export_name = "canister_query controller_download_named_state_snapshot"]
+extern "C" fn controller_download_named_state_snapshot() {
+    caller_is_controller_gaurd();
+
+    let (memory_id, label, offset, length) = arg_data::<(u8, String, u64, u64)>();
+
+    reply_result::<ByteBuf>(controller_memory_id(memory_id).and_then(|_| {
+        controller_check_label(&label)?;
+        let info: NamedSnapshotInfo = get_named_snapshot_info(memory_id, &label).ok_or(ControllerError::NamedSnapshotNotFound{ label: label.clone() })?;
+        check_reply_length(length)?;
+        check_range(offset, length, info.length)?;
+        Ok(ByteBuf::from(read_named_snapshot(memory_id, &label, offset, length).unwrap_or_default()))
+    }));
+}
+
+#[export_name = "canister_query controller_download_named_state_snapshot_chunk"]
+extern "C" fn controller_download_named_state_snapshot_chunk() {
+    caller_is_controller_gaurd();
+
+    let (memory_id, label, offset, max_length) = arg_data::<(u8, String, u64, u64)>();
+
+    reply_result::<DownloadChunk>(controller_memory_id(memory_id).and_then(|_| {
+        controller_check_label(&label)?;
+        let info: NamedSnapshotInfo = get_named_snapshot_info(memory_id, &label).ok_or(ControllerError::NamedSnapshotNotFound{ label: label.clone() })?;
+        let length: u64 = download_chunk_length(offset, max_length, info.length)?;
+        Ok(DownloadChunk{
+            bytes: ByteBuf::from(read_named_snapshot(memory_id, &label, offset, length).unwrap_or_default()),
+            total_length: info.length,
+            next_offset: offset + length,
+        })
+    }));
+}
+
+#[export_name = "canister_update controller_delete_named_state_snapshot"]
+extern "C" fn controller_delete_named_state_snapshot() {
+    caller_is_controller_gaurd();
+
+    let (memory_id, label) = arg_data::<(u8, String)>();
+
+    reply_result::<()>(controller_memory_id(memory_id).and_then(|_| {
+        controller_check_label(&label)?;
+        if !remove_named_snapshot(memory_id, &label) {
+            return Err(ControllerError::NamedSnapshotNotFound{ label });
+        }
+        Ok(())
+    }));
+}
+
+#[export_name = "canister_update controller_load_named_state_snapshot"]
+extern "C" fn controller_load_named_state_snapshot() {
+    caller_is_controller_gaurd();
+
+    let (memory_id, label) = arg_data::<(u8, String)>();
+
+    reply_result::<()>(with_snapshot_data(memory_id, |_, d| {
+        controller_check_label(&label)?;
+        let (info, bytes) = read_whole_named_snapshot(d.memory_id, &label).ok_or(ControllerError::NamedSnapshotNotFound{ label: label.clone() })?;
+        let new_data: Box<dyn Any> = decode_snapshot_bytes(d, info.compression, &bytes).0?;
+        load_state_snapshot(d, new_data)
+    }));
+}
+
+
+#[export_name = "canister_query controller_check_invariants"]
+extern "C" fn controller_check_invariants() {
+    caller_is_controller_gaurd();
+
+    let (memory_id,) = arg_data::<(u8,)>();
+
+    reply_result::<Vec<String>>(
+        controller_memory_id(memory_id)
+            .and_then(|memory_id_| check_invariants(memory_id_).map_err(|_| ControllerError::UnknownMemoryId{ memory_id }))
+    );
+}
+
+#[export_name = "canister_update controller_rollback_state_snapshot"]
+extern "C" fn controller_rollback_state_snapshot() {
+    caller_is_controller_gaurd();
+
+    let (memory_id,) = arg_data::<(u8,)>();
+
+    reply_result::<()>(with_snapshot_data(memory_id, |_, d| rollback_state_snapshot(d)));
+}
+
+// Loads the newest rollback point onto the global variable. 
+// The rollback point is loaded without the invariant checks, it is the state from before the last load.
+fn rollback_state_snapshot(d: &SnapshotData) -> Result<(), ControllerError> {
+    if d.rollback_limit == 0 {
+        return Err(ControllerError::NoRollbackPoint);
+    }
+    let (sequence, rollback_point) = pop_rollback_point(d.memory_id).ok_or(ControllerError::NoRollbackPoint)?;
+    let result: Result<Box<dyn Any>, ControllerError> = decompress_bytes(rollback_point.compression, &rollback_point.bytes)
+        .map_err(ControllerError::Compression)
+        .and_then(|b| (d.decode_bytes_fn)(&b).map_err(ControllerError::Decode));
+    match result {
+        Ok(data) => {
+            (d.set_data_fn)(data);
+            Ok(())
+        }
+        Err(e) => {
+            // the rollback point stays for a retry with a fixed decoder.
+            put_back_rollback_point(d.memory_id, sequence, rollback_point);
+            Err(e)
+        }
+    }
 }
 
 
 // ----------- STABLE-MEMORY CONTROLLER METHODS -----------
 
+fn stable_memory_size_bytes(memory: &VirtualMemory<DefaultMemoryImpl>) -> u64 {
+    memory.size() * WASM_PAGE_SIZE_IN_BYTES as u64
+}
+
 #[export_name = "canister_query controller_stable_memory_read"]
 extern "C" fn controller_stable_memory_read() {
     caller_is_controller_gaurd();
-    
+
     let (memory_id, offset, length) = arg_data::<(u8, u64, u64)>();
-    
-    let mut b: Vec<u8> = vec![0; length.try_into().unwrap()];
-    
-    get_virtual_memory(MemoryId::new(memory_id)).read(offset, &mut b);
-    
-    reply::<(ByteBuf,)>((ByteBuf::from(b),));
-    
+
+    reply_result::<ByteBuf>(controller_memory_id(memory_id).and_then(|memory_id| {
+        check_reply_length(length)?;
+        let memory = get_virtual_memory(memory_id);
+        check_range(offset, length, stable_memory_size_bytes(&memory))?;
+        let mut b: Vec<u8> = vec![0; length as usize];
+        memory.read(offset, &mut b);
+        Ok(ByteBuf::from(b))
+    }));
 }
 
 #[export_name = "canister_update controller_stable_memory_write"]
@@ -321,11 +2012,13 @@ extern "C" fn controller_stable_memory_write() {
     caller_is_controller_gaurd();
 
     let (memory_id, offset, b) = arg_data::<(u8, u64, ByteBuf)>();
-        
-    get_virtual_memory(MemoryId::new(memory_id)).write(offset, &b);
-    
-    reply::<()>(());
-    
+
+    reply_result::<()>(controller_memory_id(memory_id).and_then(|memory_id| {
+        let memory = get_virtual_memory(memory_id);
+        check_range(offset, b.len() as u64, stable_memory_size_bytes(&memory))?;
+        memory.write(offset, &b);
+        Ok(())
+    }));
 }
 
 
@@ -334,9 +2027,8 @@ extern "C" fn controller_stable_memory_size() {
     caller_is_controller_gaurd();
 
     let (memory_id,) = arg_data::<(u8,)>();
-        
-    reply::<(u64,)>((get_virtual_memory(MemoryId::new(memory_id)).size(),));
-    
+
+    reply_result::<u64>(controller_memory_id(memory_id).map(|memory_id| get_virtual_memory(memory_id).size()));
 }
 
 
@@ -345,12 +2037,704 @@ extern "C" fn controller_stable_memory_grow() {
     caller_is_controller_gaurd();
 
     let (memory_id, pages) = arg_data::<(u8, u64)>();
-        
-    reply::<(i64,)>((get_virtual_memory(MemoryId::new(memory_id)).grow(pages),));
-    
+
+    reply_result::<u64>(controller_memory_id(memory_id).and_then(|memory_id| {
+        let previous_size: i64 = get_virtual_memory(memory_id).grow(pages);
+        if previous_size < 0 {
+            return Err(ControllerError::StableMemoryGrowFailed{ pages });
+        }
+        Ok(previous_size as u64)
+    }));
 }
 
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::header::sha256;
+
+    fn test_memory() -> VirtualMemory<DefaultMemoryImpl> {
+        let memory = get_virtual_memory(MemoryId::new(0));
+        memory.grow(1);
+        memory
+    }
+
+    // Writes the payload with its length at the offset, returns the header of the payload.
+    fn write_test_payload(memory: &VirtualMemory<DefaultMemoryImpl>, payload_offset: u64, sequence: u64, payload: &[u8]) -> PayloadHeader {
+        memory.write(payload_offset, &(payload.len() as u64).to_be_bytes());
+        memory.write(payload_offset + 8, payload);
+        PayloadHeader {
+            format_id: SerializationFormat::Candid.id(),
+            compression_id: Compression::None.id(),
+            payload_offset,
+            payload_length: payload.len() as u64,
+            checksum: sha256(payload),
+            type_fingerprint: 0,
+            timestamp_nanos: 0,
+            data_version: 0,
+            sequence,
+            migration_log: Vec::new(),
+        }
+    }
+
+    fn read_test_payload(memory_id: MemoryId) -> Result<(u64, Vec<u8>), UpgradeError> {
+        let (opt_header, mut payload_reader) = read_verified_payload(memory_id)?;
+        let mut payload: Vec<u8> = Vec::new();
+        payload_reader.read_to_end(&mut payload).unwrap();
+        Ok((opt_header.unwrap().sequence, payload))
+    }
+
+    fn serialize_bytes_fn(bytes: Vec<u8>) -> SerializeDataIntoFn {
+        Box::new(move |w| w.write_all(&bytes).map_err(|e| format!("{:?}", e)))
+    }
+
+    #[derive(CandidType, Deserialize, serde::Serialize, Clone, Debug, PartialEq)]
+    struct TestData {
+        field_one: String,
+        field_two: u64,
+    }
+
+    #[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+    struct OldTestData {
+        field_one: String,
+    }
+
+    thread_local!{
+        static DATA: RefCell<TestData> = RefCell::new(TestData{ field_one: "Hi World".to_string(), field_two: 55 });
+        static OLD_DATA: RefCell<OldTestData> = RefCell::new(OldTestData{ field_one: "Hi Old World".to_string() });
+    }
+
+    fn set_test_data(field_two: u64) {
+        DATA.with(|data| data.borrow_mut().field_two = field_two);
+    }
+
+    fn test_data_field_two() -> u64 {
+        DATA.with(|data| data.borrow().field_two)
+    }
+
+    // Drops the registrations like an upgrade does, the stable memory stays.
+    fn simulate_upgrade() {
+        with_mut(&STATE_SNAPSHOTS, |state_snapshots| state_snapshots.clear());
+    }
+
+    fn is_registered(memory_id: MemoryId) -> bool {
+        with(&STATE_SNAPSHOTS, |state_snapshots| state_snapshots.contains_key(&memory_id))
+    }
+
+    #[test]
+    fn placement_without_a_payload() {
+        let memory = test_memory();
+        let placement = next_payload_placement(&memory, MemoryId::new(0));
+        assert!(placement.opt_live.is_none());
+        assert_eq!((placement.slot, placement.sequence), (0, 1));
+        assert_eq!(placement.regions, vec![(STABLE_MEMORY_HEADER_SIZE_BYTES, None)]);
+    }
+
+    #[test]
+    fn placement_after_the_live_payload() {
+        let memory = test_memory();
+        let live = write_test_payload(&memory, STABLE_MEMORY_HEADER_SIZE_BYTES, 1, &[1; 100]);
+        write_header(&memory, 0, &live, None);
+        let placement = next_payload_placement(&memory, MemoryId::new(0));
+        assert_eq!((placement.slot, placement.sequence), (1, 2));
+        assert_eq!(placement.regions, vec![(STABLE_MEMORY_HEADER_SIZE_BYTES + 8 + 100, None)]);
+    }
+
+    #[test]
+    fn placement_before_the_live_payload() {
+        let memory = test_memory();
+        let older = write_test_payload(&memory, STABLE_MEMORY_HEADER_SIZE_BYTES, 1, &[1; 100]);
+        let live = write_test_payload(&memory, STABLE_MEMORY_HEADER_SIZE_BYTES + 8 + 100, 2, &[2; 50]);
+        write_header(&memory, 1, &live, Some(&older));
+        let placement = next_payload_placement(&memory, MemoryId::new(0));
+        assert_eq!((placement.slot, placement.sequence), (0, 3));
+        assert_eq!(placement.regions, vec![
+            (STABLE_MEMORY_HEADER_SIZE_BYTES, Some(100)),
+            (STABLE_MEMORY_HEADER_SIZE_BYTES + 8 + 100 + 8 + 50, None),
+        ]);
+    }
+
+    #[test]
+    fn placement_ignores_a_live_payload_past_the_memory() {
+        let memory = test_memory();
+        let live = PayloadHeader{ payload_length: WASM_PAGE_SIZE_IN_BYTES as u64, ..write_test_payload(&memory, STABLE_MEMORY_HEADER_SIZE_BYTES, 1, &[1; 100]) };
+        write_header(&memory, 0, &live, None);
+        let placement = next_payload_placement(&memory, MemoryId::new(0));
+        assert!(placement.opt_live.is_none());
+        assert_eq!((placement.slot, placement.sequence), (0, 1));
+    }
+
+    #[test]
+    fn limited_write_does_not_touch_the_live_payload() {
+        let memory = test_memory();
+        let live = write_test_payload(&memory, STABLE_MEMORY_HEADER_SIZE_BYTES + 8 + 100, 1, &[2; 50]);
+        let serialize_data_into_fn = serialize_bytes_fn(vec![3; 200]);
+        // the payload does not fit in the region before the live payload.
+        assert_eq!(
+            write_data_with_length_onto_the_stable_memory(&memory, STABLE_MEMORY_HEADER_SIZE_BYTES, &serialize_data_into_fn, Compression::None, Some(100)),
+            Ok(None)
+        );
+        assert!(verify_payload_slot(&memory, MemoryId::new(0), &live).is_ok());
+        // the retry after the live payload.
+        let payload_offset: u64 = live.payload_offset + 8 + live.payload_length;
+        assert_eq!(
+            write_data_with_length_onto_the_stable_memory(&memory, payload_offset, &serialize_data_into_fn, Compression::None, None),
+            Ok(Some((200, sha256(&[3; 200]))))
+        );
+        assert_eq!(read_stable_memory_payload_length(&memory, payload_offset), Ok(200));
+    }
+
+    #[test]
+    fn streaming_write_grows_the_memory_and_reads_back() {
+        let memory = test_memory();
+        // spans a few wasm pages and many buffers.
+        let payload: Vec<u8> = (0..3 * WASM_PAGE_SIZE_IN_BYTES as u64 + 7).map(|i| (i % 251) as u8).collect();
+        assert_eq!(
+            write_data_with_length_onto_the_stable_memory(&memory, STABLE_MEMORY_HEADER_SIZE_BYTES, &serialize_bytes_fn(payload.clone()), Compression::None, None),
+            Ok(Some((payload.len() as u64, sha256(&payload))))
+        );
+        assert!(memory.size() * WASM_PAGE_SIZE_IN_BYTES as u64 >= STABLE_MEMORY_HEADER_SIZE_BYTES + 8 + payload.len() as u64);
+        assert_eq!(read_stable_memory_payload_length(&memory, STABLE_MEMORY_HEADER_SIZE_BYTES), Ok(payload.len() as u64));
+        let mut read_payload: Vec<u8> = Vec::new();
+        payload_reader(&memory, STABLE_MEMORY_HEADER_SIZE_BYTES, payload.len() as u64).read_to_end(&mut read_payload).unwrap();
+        assert!(read_payload == payload);
+    }
+
+    #[test]
+    fn pre_upgrade_streams_the_data_without_a_heap_snapshot() {
+        init(&DATA, MemoryId::new(0));
+        pre_upgrade();
+        assert!(with(&STATE_SNAPSHOTS, |state_snapshots| state_snapshots.get(&MemoryId::new(0)).unwrap().snapshot.is_empty()));
+        let (_, payload) = read_test_payload(MemoryId::new(0)).unwrap();
+        assert_eq!(candid::decode_one::<TestData>(&payload).unwrap(), DATA.with(|data| data.borrow().clone()));
+    }
+
+    #[test]
+    fn read_the_newest_payload() {
+        let memory = test_memory();
+        let older = write_test_payload(&memory, STABLE_MEMORY_HEADER_SIZE_BYTES, 1, &[1; 100]);
+        let newer = write_test_payload(&memory, STABLE_MEMORY_HEADER_SIZE_BYTES + 8 + 100, 2, &[2; 50]);
+        write_header(&memory, 1, &newer, Some(&older));
+        assert_eq!(read_test_payload(MemoryId::new(0)), Ok((2, vec![2; 50])));
+    }
+
+    #[test]
+    fn read_the_older_payload_on_a_checksum_mismatch() {
+        let memory = test_memory();
+        let older = write_test_payload(&memory, STABLE_MEMORY_HEADER_SIZE_BYTES, 1, &[1; 100]);
+        let newer = write_test_payload(&memory, STABLE_MEMORY_HEADER_SIZE_BYTES + 8 + 100, 2, &[2; 50]);
+        write_header(&memory, 1, &newer, Some(&older));
+        // a torn write of the newer payload.
+        memory.write(newer.payload_offset + 8, &[9; 10]);
+        assert_eq!(read_test_payload(MemoryId::new(0)), Ok((1, vec![1; 100])));
+    }
+
+    #[test]
+    fn read_the_older_payload_on_an_offset_out_of_range() {
+        let memory = test_memory();
+        let older = write_test_payload(&memory, STABLE_MEMORY_HEADER_SIZE_BYTES, 1, &[1; 100]);
+        let newer = PayloadHeader{ payload_offset: u64::MAX - 4, ..write_test_payload(&memory, STABLE_MEMORY_HEADER_SIZE_BYTES + 8 + 100, 2, &[2; 50]) };
+        write_header(&memory, 1, &newer, Some(&older));
+        assert!(read_stable_memory_payload_length(&memory, newer.payload_offset).is_err());
+        assert_eq!(read_test_payload(MemoryId::new(0)), Ok((1, vec![1; 100])));
+    }
+
+    #[test]
+    fn read_the_older_payload_on_an_unknown_compression() {
+        let memory = test_memory();
+        let older = write_test_payload(&memory, STABLE_MEMORY_HEADER_SIZE_BYTES, 1, &[1; 100]);
+        let newer = PayloadHeader{ compression_id: 200, ..write_test_payload(&memory, STABLE_MEMORY_HEADER_SIZE_BYTES + 8 + 100, 2, &[2; 50]) };
+        write_header(&memory, 1, &newer, Some(&older));
+        assert_eq!(read_test_payload(MemoryId::new(0)), Ok((1, vec![1; 100])));
+    }
+
+    #[test]
+    fn read_returns_the_error_of_the_newest_payload() {
+        let memory = test_memory();
+        let older = write_test_payload(&memory, STABLE_MEMORY_HEADER_SIZE_BYTES, 1, &[1; 100]);
+        let newer = write_test_payload(&memory, STABLE_MEMORY_HEADER_SIZE_BYTES + 8 + 100, 2, &[2; 50]);
+        write_header(&memory, 1, &newer, Some(&older));
+        memory.write(older.payload_offset + 8, &[9; 10]);
+        memory.write(newer.payload_offset + 8, &[9; 10]);
+        assert_eq!(read_test_payload(MemoryId::new(0)), Err(UpgradeError::ChecksumMismatch{ memory_id: MemoryId::new(0) }));
+    }
+
+    #[test]
+    fn try_post_upgrade_loads_the_payload() {
+        set_test_data(5);
+        init(&DATA, MemoryId::new(0));
+        simulate_upgrade();
+        set_test_data(6);
+        assert_eq!(try_post_upgrade(&DATA, MemoryId::new(0), None::<fn(TestData) -> TestData>), Ok(()));
+        assert_eq!(test_data_field_two(), 5);
+        assert!(is_registered(MemoryId::new(0)));
+    }
+
+    #[test]
+    fn try_post_upgrade_converts_the_old_data() {
+        init(&OLD_DATA, MemoryId::new(0));
+        simulate_upgrade();
+        let old_as_new_convert = |old_data: OldTestData| TestData{ field_one: old_data.field_one, field_two: 7 };
+        assert_eq!(try_post_upgrade(&DATA, MemoryId::new(0), Some(old_as_new_convert)), Ok(()));
+        assert_eq!(DATA.with(|data| data.borrow().clone()), TestData{ field_one: "Hi Old World".to_string(), field_two: 7 });
+    }
+
+    #[test]
+    fn try_post_upgrade_on_an_empty_memory() {
+        assert_eq!(
+            try_post_upgrade(&DATA, MemoryId::new(0), None::<fn(TestData) -> TestData>),
+            Err(UpgradeError::EmptyMemory{ memory_id: MemoryId::new(0) })
+        );
+        assert_eq!(test_data_field_two(), 55);
+        assert!(!is_registered(MemoryId::new(0)));
+    }
+
+    #[test]
+    fn try_post_upgrade_with_the_wrong_type() {
+        init(&OLD_DATA, MemoryId::new(0));
+        simulate_upgrade();
+        match try_post_upgrade(&DATA, MemoryId::new(0), None::<fn(TestData) -> TestData>) {
+            Err(UpgradeError::Decode{ memory_id, written_by_same_type, .. }) => {
+                assert_eq!((memory_id, written_by_same_type), (MemoryId::new(0), Some(false)));
+            }
+            r => panic!("{:?}", r),
+        }
+        assert_eq!(test_data_field_two(), 55);
+        assert!(!is_registered(MemoryId::new(0)));
+    }
+
+    #[test]
+    fn try_post_upgrade_on_a_registered_or_reserved_memory_id() {
+        init(&DATA, MemoryId::new(0));
+        assert_eq!(
+            try_post_upgrade(&DATA, MemoryId::new(0), None::<fn(TestData) -> TestData>),
+            Err(UpgradeError::AlreadyRegistered{ memory_id: MemoryId::new(0) })
+        );
+        assert_eq!(
+            try_post_upgrade(&OLD_DATA, ROLLBACK_POINTS_MEMORY_ID, None::<fn(OldTestData) -> OldTestData>),
+            Err(UpgradeError::ReservedMemoryId{ memory_id: ROLLBACK_POINTS_MEMORY_ID })
+        );
+    }
+
+    fn test_migrations() -> Migrations<TestData> {
+        Migrations::<OldTestData>::new().then(|old_data: OldTestData| TestData{ field_one: old_data.field_one, field_two: 8 })
+    }
+
+    fn newest_payload_header(memory_id: MemoryId) -> PayloadHeader {
+        read_header(&get_virtual_memory(memory_id), memory_id).unwrap().unwrap().remove(0).1
+    }
+
+    #[test]
+    fn try_post_upgrade_with_migrations_applies_and_records_the_steps() {
+        init(&OLD_DATA, MemoryId::new(0));
+        simulate_upgrade();
+        assert_eq!(try_post_upgrade_with_migrations(&DATA, MemoryId::new(0), test_migrations()), Ok(()));
+        assert_eq!(DATA.with(|data| data.borrow().clone()), TestData{ field_one: "Hi Old World".to_string(), field_two: 8 });
+        let payload_header = newest_payload_header(MemoryId::new(0));
+        assert_eq!(payload_header.data_version, 1);
+        assert_eq!(payload_header.migration_log, vec![MigrationRecord{ from_version: 0, to_version: 1, timestamp_nanos: 0 }]);
+        // the next upgrade is at the latest data version, no step is applied.
+        set_test_data(9);
+        pre_upgrade();
+        simulate_upgrade();
+        set_test_data(0);
+        assert_eq!(try_post_upgrade_with_migrations(&DATA, MemoryId::new(0), test_migrations()), Ok(()));
+        assert_eq!(test_data_field_two(), 9);
+        assert_eq!(newest_payload_header(MemoryId::new(0)).migration_log.len(), 1);
+    }
+
+    #[test]
+    fn try_post_upgrade_with_migrations_on_an_unknown_data_version() {
+        init_with_migrations(&DATA, MemoryId::new(0), &test_migrations());
+        simulate_upgrade();
+        assert_eq!(
+            try_post_upgrade_with_migrations(&DATA, MemoryId::new(0), Migrations::<TestData>::starting_at(2)),
+            Err(UpgradeError::UnknownDataVersion{ memory_id: MemoryId::new(0), data_version: 1 })
+        );
+        // a payload at data version 1 is not loaded by post_upgrade, which is at data version 0.
+        assert_eq!(
+            try_post_upgrade(&DATA, MemoryId::new(0), None::<fn(TestData) -> TestData>),
+            Err(UpgradeError::UnknownDataVersion{ memory_id: MemoryId::new(0), data_version: 1 })
+        );
+    }
+
+    // A hand-written codec for a type that also implements CandidType.
+    struct TestCodec;
+
+    impl UpgradeCodec<TestData> for TestCodec {
+        fn forward(data: &TestData) -> Result<Vec<u8>, String> {
+            let mut b: Vec<u8> = data.field_two.to_be_bytes().to_vec();
+            b.extend_from_slice(data.field_one.as_bytes());
+            Ok(b)
+        }
+        fn backward(b: &[u8]) -> Result<TestData, String> {
+            if b.len() < 8 {
+                return Err("the payload is too short".to_string());
+            }
+            Ok(TestData{
+                field_one: String::from_utf8(b[8..].to_vec()).map_err(|e| format!("{:?}", e))?,
+                field_two: u64::from_be_bytes(b[..8].try_into().unwrap()),
+            })
+        }
+    }
+
+    #[test]
+    fn custom_codec_round_trip() {
+        init_with_codec::<TestData, TestCodec>(&DATA, MemoryId::new(0));
+        set_test_data(77);
+        pre_upgrade();
+        assert_eq!(newest_payload_header(MemoryId::new(0)).format_id, SerializationFormat::Custom.id());
+        simulate_upgrade();
+        set_test_data(0);
+        assert_eq!(try_post_upgrade_with_codec::<TestData, TestCodec>(&DATA, MemoryId::new(0)), Ok(()));
+        assert_eq!(test_data_field_two(), 77);
+    }
+
+    #[test]
+    fn custom_codec_payload_is_not_loaded_as_candid() {
+        init_with_codec::<TestData, TestCodec>(&DATA, MemoryId::new(0));
+        simulate_upgrade();
+        assert_eq!(
+            try_post_upgrade(&DATA, MemoryId::new(0), None::<fn(TestData) -> TestData>),
+            Err(UpgradeError::FormatMismatch{ memory_id: MemoryId::new(0), stored_format_id: SerializationFormat::Custom.id(), expected_format: SerializationFormat::Candid })
+        );
+        assert!(!is_registered(MemoryId::new(0)));
+    }
+
+    // The slot and the sequence of the newest payload.
+    fn newest_payload_slot(memory_id: MemoryId) -> (usize, u64) {
+        let (slot, payload_header) = read_header(&get_virtual_memory(memory_id), memory_id).unwrap().unwrap().remove(0);
+        (slot, payload_header.sequence)
+    }
+
+    #[cfg(feature = "checkpoints")]
+    #[test]
+    fn checkpoints_alternate_the_payload_slots() {
+        init(&DATA, MemoryId::new(0));
+        assert_eq!(newest_payload_slot(MemoryId::new(0)), (0, 1));
+        assert_eq!(write_checkpoint(MemoryId::new(0)), Ok(()));
+        assert_eq!(newest_payload_slot(MemoryId::new(0)), (1, 2));
+        assert_eq!(write_checkpoint(MemoryId::new(0)), Ok(()));
+        assert_eq!(newest_payload_slot(MemoryId::new(0)), (0, 3));
+        assert!(write_checkpoint(MemoryId::new(1)).is_err());
+    }
+
+    #[cfg(feature = "checkpoints")]
+    #[test]
+    fn checkpoint_is_loaded_after_an_upgrade_that_skips_the_pre_upgrade() {
+        init(&DATA, MemoryId::new(0));
+        set_test_data(12);
+        assert_eq!(write_checkpoint(MemoryId::new(0)), Ok(()));
+        set_test_data(13);
+        // no pre_upgrade.
+        simulate_upgrade();
+        assert_eq!(try_post_upgrade(&DATA, MemoryId::new(0), None::<fn(TestData) -> TestData>), Ok(()));
+        assert_eq!(test_data_field_two(), 12);
+    }
+
+    fn data_generation() -> u64 {
+        generation(&DATA as *const LocalKey<RefCell<TestData>> as usize).generation
+    }
+
+    #[test]
+    fn with_mut_increments_the_generation_of_a_registered_global() {
+        // not tracked before the registration.
+        with_mut(&DATA, |data| data.field_two = 1);
+        assert_eq!(data_generation(), 0);
+        init(&DATA, MemoryId::new(0));
+        with(&DATA, |data| assert_eq!(data.field_two, 1));
+        assert_eq!(data_generation(), 0);
+        with_mut(&DATA, |data| data.field_two = 2);
+        with_mut(&DATA, |data| data.field_two = 3);
+        assert_eq!(data_generation(), 2);
+    }
+
+    #[test]
+    fn pre_upgrade_skips_a_clean_global_with_the_dirty_tracking() {
+        init(&DATA, MemoryId::new(0));
+        set_dirty_tracking(MemoryId::new(0), true);
+        pre_upgrade();
+        assert_eq!(newest_payload_slot(MemoryId::new(0)), (0, 1));
+        with_mut(&DATA, |data| data.field_two = 21);
+        pre_upgrade();
+        assert_eq!(newest_payload_slot(MemoryId::new(0)), (1, 2));
+        pre_upgrade();
+        assert_eq!(newest_payload_slot(MemoryId::new(0)), (1, 2));
+        simulate_upgrade();
+        set_test_data(0);
+        assert_eq!(try_post_upgrade(&DATA, MemoryId::new(0), None::<fn(TestData) -> TestData>), Ok(()));
+        assert_eq!(test_data_field_two(), 21);
+    }
+
+    #[test]
+    fn pre_upgrade_writes_a_clean_global_without_the_dirty_tracking() {
+        init(&DATA, MemoryId::new(0));
+        pre_upgrade();
+        assert_eq!(newest_payload_slot(MemoryId::new(0)), (1, 2));
+    }
+
+    #[test]
+    fn check_upgrade_readiness_reports_each_memory_id_without_writing() {
+        init(&DATA, MemoryId::new(0));
+        init(&OLD_DATA, MemoryId::new(1));
+        // the next payload of memory-id 1 does not fit in the page after the live payload.
+        with_mut(&OLD_DATA, |old_data| old_data.field_one = "a".repeat(WASM_PAGE_SIZE_IN_BYTES));
+        let newest_payload_slots = (newest_payload_slot(MemoryId::new(0)), newest_payload_slot(MemoryId::new(1)));
+        let upgrade_readiness = check_upgrade_readiness();
+        assert!(upgrade_readiness.ready);
+        assert_eq!(upgrade_readiness.memory_ids.len(), 2);
+        let (data_readiness, old_data_readiness) = (&upgrade_readiness.memory_ids[0], &upgrade_readiness.memory_ids[1]);
+        assert_eq!(data_readiness.memory_id, 0);
+        assert_eq!(data_readiness.payload_length, candid::encode_one(DATA.with(|data| data.borrow().clone())).unwrap().len() as u64);
+        assert_eq!(data_readiness.stable_memory_growth_wasm_pages, 0);
+        assert!(data_readiness.modified && data_readiness.error.is_none());
+        assert_eq!(old_data_readiness.memory_id, 1);
+        assert_eq!(old_data_readiness.stable_memory_growth_wasm_pages, 1);
+        assert_eq!(upgrade_readiness.total_stable_memory_growth_wasm_pages, 1);
+        // nothing is written.
+        assert_eq!((newest_payload_slot(MemoryId::new(0)), newest_payload_slot(MemoryId::new(1))), newest_payload_slots);
+        assert_eq!(get_virtual_memory(MemoryId::new(1)).size(), 1);
+    }
+
+    #[test]
+    fn check_upgrade_readiness_leaves_out_a_clean_global() {
+        init(&OLD_DATA, MemoryId::new(1));
+        set_dirty_tracking(MemoryId::new(1), true);
+        let upgrade_readiness = check_upgrade_readiness();
+        assert!(!upgrade_readiness.memory_ids[0].modified);
+        assert_eq!(upgrade_readiness.total_instructions, 0);
+        assert_eq!(upgrade_readiness.total_stable_memory_growth_wasm_pages, 0);
+        with_mut(&OLD_DATA, |old_data| old_data.field_one = "a".repeat(WASM_PAGE_SIZE_IN_BYTES));
+        assert_eq!(check_upgrade_readiness().total_stable_memory_growth_wasm_pages, 1);
+    }
+
+    fn verify(memory_id: MemoryId) -> UpgradePayloadVerification {
+        with(&STATE_SNAPSHOTS, |state_snapshots| verify_upgrade_payload(memory_id, state_snapshots.get(&memory_id).unwrap())).unwrap()
+    }
+
+    #[test]
+    fn verify_upgrade_payload_does_not_load_the_data() {
+        init(&DATA, MemoryId::new(0));
+        set_test_data(31);
+        let upgrade_payload_verification = verify(MemoryId::new(0));
+        assert_eq!(upgrade_payload_verification.error, None);
+        assert_eq!(upgrade_payload_verification.payload_length, newest_payload_header(MemoryId::new(0)).payload_length);
+        assert_eq!(upgrade_payload_verification.timestamp_nanos, Some(0));
+        assert_eq!(test_data_field_two(), 31);
+    }
+
+    #[test]
+    fn verify_upgrade_payload_of_another_type() {
+        init(&OLD_DATA, MemoryId::new(0));
+        simulate_upgrade();
+        register::<TestData, SerializableCodec>(&DATA, MemoryId::new(0), 0, Vec::new()).unwrap();
+        let error: String = verify(MemoryId::new(0)).error.unwrap();
+        assert!(error.contains("the payload was written by a different type than the type being loaded"), "{}", error);
+    }
+
+    #[cfg(any(feature = "cbor", feature = "bincode", feature = "msgpack"))]
+    macro_rules! verify_upgrade_payload_tests {
+        ($test_name:ident, $codec:ty) => {
+            #[test]
+            fn $test_name() {
+                init_with_codec::<TestData, $codec>(&DATA, MemoryId::new(0));
+                assert_eq!(newest_payload_header(MemoryId::new(0)).format_id, <$codec as UpgradeCodec<TestData>>::format().id());
+                assert_eq!(verify(MemoryId::new(0)).error, None);
+                // the payload is not read as candid.
+                simulate_upgrade();
+                register::<TestData, SerializableCodec>(&DATA, MemoryId::new(0), 0, Vec::new()).unwrap();
+                assert_eq!(
+                    verify(MemoryId::new(0)).error,
+                    Some(UpgradeError::FormatMismatch{ memory_id: MemoryId::new(0), stored_format_id: <$codec as UpgradeCodec<TestData>>::format().id(), expected_format: SerializationFormat::Candid }.to_string())
+                );
+            }
+        };
+    }
+
+    #[cfg(feature = "cbor")]
+    verify_upgrade_payload_tests!(verify_upgrade_payload_cbor, crate::formats::CborCodec);
+
+    #[cfg(feature = "bincode")]
+    verify_upgrade_payload_tests!(verify_upgrade_payload_bincode, crate::formats::BincodeCodec);
+
+    #[cfg(feature = "msgpack")]
+    verify_upgrade_payload_tests!(verify_upgrade_payload_message_pack, crate::formats::MessagePackCodec);
+
+    fn validate_snapshot_bytes(memory_id: MemoryId, snapshot: Vec<u8>) -> StateSnapshotValidation {
+        with_mut(&STATE_SNAPSHOTS, |state_snapshots| {
+            let d = state_snapshots.get_mut(&memory_id).unwrap();
+            d.clear_snapshot(Compression::None);
+            d.snapshot = snapshot;
+            validate_state_snapshot(d)
+        })
+    }
+
+    fn test_data_with_field_two(field_two: u64) -> Vec<u8> {
+        candid::encode_one(TestData{ field_one: "Hi Snapshot".to_string(), field_two }).unwrap()
+    }
+
+    #[test]
+    fn validate_state_snapshot_of_a_created_snapshot() {
+        init(&DATA, MemoryId::new(0));
+        let validation = with_mut(&STATE_SNAPSHOTS, |state_snapshots| {
+            let d = state_snapshots.get_mut(&MemoryId::new(0)).unwrap();
+            create_state_snapshot(d, false).unwrap();
+            validate_state_snapshot(d)
+        });
+        assert_eq!(validation.error, None);
+        assert!(validation.failed_checks.is_empty());
+        assert_eq!(validation.decoded_length, validation.snapshot_length);
+        assert_eq!(validation.snapshot_length, candid::encode_one(DATA.with(|data| data.borrow().clone())).unwrap().len() as u64);
+    }
+
+    #[test]
+    fn validate_state_snapshot_of_bytes_that_do_not_decode() {
+        init(&DATA, MemoryId::new(0));
+        let validation = validate_snapshot_bytes(MemoryId::new(0), candid::encode_one(OLD_DATA.with(|old_data| old_data.borrow().clone())).unwrap());
+        assert!(validation.error.is_some());
+        assert!(validation.failed_checks.is_empty());
+        assert_eq!(validate_snapshot_bytes(MemoryId::new(0), vec![1, 2, 3]).decoded_length, 3);
+    }
+
+    #[test]
+    fn validate_state_snapshot_runs_the_invariant_checks() {
+        init(&DATA, MemoryId::new(0));
+        add_invariant(&DATA, MemoryId::new(0), "field-two-below-100", |data: &TestData| {
+            if data.field_two >= 100 {
+                return Err(format!("field_two is {}", data.field_two));
+            }
+            Ok(())
+        });
+        assert!(validate_snapshot_bytes(MemoryId::new(0), test_data_with_field_two(99)).failed_checks.is_empty());
+        let validation = validate_snapshot_bytes(MemoryId::new(0), test_data_with_field_two(100));
+        assert_eq!(validation.error, None);
+        assert_eq!(validation.failed_checks, vec!["field-two-below-100: field_two is 100".to_string()]);
+        // the global variable is not touched.
+        assert_eq!(test_data_field_two(), 55);
+    }
+
+    fn load_snapshot_bytes(memory_id: MemoryId, snapshot: &[u8]) -> Result<(), ControllerError> {
+        with(&STATE_SNAPSHOTS, |state_snapshots| {
+            let d = state_snapshots.get(&memory_id).unwrap();
+            load_state_snapshot(d, (d.decode_bytes_fn)(snapshot).unwrap())
+        })
+    }
+
+    fn rollback(memory_id: MemoryId) -> Result<(), ControllerError> {
+        with(&STATE_SNAPSHOTS, |state_snapshots| rollback_state_snapshot(state_snapshots.get(&memory_id).unwrap()))
+    }
+
+    #[test]
+    fn load_without_rollback_points() {
+        init(&DATA, MemoryId::new(0));
+        assert_eq!(load_snapshot_bytes(MemoryId::new(0), &test_data_with_field_two(1)), Ok(()));
+        assert_eq!(test_data_field_two(), 1);
+        assert_eq!(rollback(MemoryId::new(0)), Err(ControllerError::NoRollbackPoint));
+        assert_eq!(test_data_field_two(), 1);
+    }
+
+    #[test]
+    fn rollback_the_loads_up_to_the_limit() {
+        init(&DATA, MemoryId::new(0));
+        set_rollback_limit(MemoryId::new(0), 2);
+        for field_two in [1, 2, 3] {
+            assert_eq!(load_snapshot_bytes(MemoryId::new(0), &test_data_with_field_two(field_two)), Ok(()));
+        }
+        assert_eq!(test_data_field_two(), 3);
+        assert_eq!(rollback(MemoryId::new(0)), Ok(()));
+        assert_eq!(test_data_field_two(), 2);
+        assert_eq!(rollback(MemoryId::new(0)), Ok(()));
+        assert_eq!(test_data_field_two(), 1);
+        // the rollback point of the state before the first load is dropped by the limit.
+        assert_eq!(rollback(MemoryId::new(0)), Err(ControllerError::NoRollbackPoint));
+        assert_eq!(test_data_field_two(), 1);
+    }
 
+    fn add_test_invariants() {
+        add_invariant(&DATA, MemoryId::new(0), "field-two-below-100", |data: &TestData| {
+            if data.field_two >= 100 {
+                return Err(format!("field_two is {}", data.field_two));
+            }
+            Ok(())
+        });
+        add_invariant(&DATA, MemoryId::new(0), "field-one-not-empty", |data: &TestData| {
+            if data.field_one.is_empty() {
+                return Err("field_one is empty".to_string());
+            }
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn check_invariants_of_the_global() {
+        init(&DATA, MemoryId::new(0));
+        add_test_invariants();
+        assert_eq!(check_invariants(MemoryId::new(0)), Ok(Vec::new()));
+        with_mut(&DATA, |data| {
+            data.field_one.clear();
+            data.field_two = 100;
+        });
+        assert_eq!(check_invariants(MemoryId::new(0)), Ok(vec![
+            "field-two-below-100: field_two is 100".to_string(),
+            "field-one-not-empty: field_one is empty".to_string(),
+        ]));
+        assert!(check_invariants(MemoryId::new(1)).is_err());
+    }
 
+    #[test]
+    fn load_that_fails_an_invariant_check_is_aborted() {
+        init(&DATA, MemoryId::new(0));
+        set_rollback_limit(MemoryId::new(0), 1);
+        add_test_invariants();
+        assert_eq!(
+            load_snapshot_bytes(MemoryId::new(0), &test_data_with_field_two(100)),
+            Err(ControllerError::InvariantChecks(vec!["field-two-below-100: field_two is 100".to_string()]))
+        );
+        assert_eq!(test_data_field_two(), 55);
+        assert_eq!(rollback(MemoryId::new(0)), Err(ControllerError::NoRollbackPoint));
+    }
 
+    #[test]
+    #[should_panic]
+    fn add_invariant_that_the_global_fails() {
+        init(&DATA, MemoryId::new(0));
+        add_invariant(&DATA, MemoryId::new(0), "field-two-below-10", |data: &TestData| {
+            if data.field_two >= 10 {
+                return Err(format!("field_two is {}", data.field_two));
+            }
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn list_state_snapshots_of_the_registered_memory_ids() {
+        init(&OLD_DATA, MemoryId::new(3));
+        init(&DATA, MemoryId::new(0));
+        let list = list_state_snapshots();
+        assert_eq!(list.iter().map(|info| info.memory_id).collect::<Vec<u8>>(), vec![0, 3]);
+        assert!(list[0].type_name.ends_with("::TestData"), "{}", list[0].type_name);
+        assert!(list[1].type_name.ends_with("::OldTestData"), "{}", list[1].type_name);
+        for info in list.iter() {
+            assert_eq!(info.snapshot_length, 0);
+            assert_eq!(info.snapshot_sha256.as_slice(), sha256(&[]));
+            assert_eq!(info.snapshot_timestamp_nanos, None);
+            assert_eq!(info.snapshot_source, None);
+        }
+        let (snapshot_length, snapshot_sha256) = with_mut(&STATE_SNAPSHOTS, |state_snapshots| {
+            create_state_snapshot(state_snapshots.get_mut(&MemoryId::new(3)).unwrap(), false).unwrap()
+        });
+        let info = list_state_snapshots().remove(1);
+        assert_eq!(info.snapshot_length, snapshot_length);
+        assert_eq!(info.snapshot_sha256.as_slice(), snapshot_sha256);
+        assert_eq!(info.snapshot_compression, Compression::None);
+        assert_eq!(info.snapshot_timestamp_nanos, Some(0));
+        assert_eq!(info.snapshot_source, Some(StateSnapshotSource::Serialization));
+    }
+
+    #[test]
+    fn memory_id_as_u8_finds_each_memory_id() {
+        for i in 0..u8::MAX {
+            assert_eq!(memory_id_as_u8(MemoryId::new(i)), i);
+        }
+    }
+}
@@ -0,0 +1,64 @@
+// The timer schedules of the checkpoints and of the invariant checks, one interval timer for each registered memory-id.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::thread::LocalKey;
+use std::time::Duration;
+
+use ic_cdk::trap;
+use ic_cdk_timers::{set_timer_interval, clear_timer, TimerId};
+
+use crate::localkey::refcell::with_mut;
+use crate::MemoryId;
+use crate::stable_memory_tools::registered_memory_id;
+
+
+pub(crate) struct TimerSchedule<S> {
+    pub memory_id: u8,
+    pub interval: Duration,
+    timer_id: TimerId,
+    pub state: S,
+}
+
+pub(crate) type TimerSchedules<S> = BTreeMap<MemoryId, TimerSchedule<S>>;
+
+
+/// Replaces the timer of the memory-id with a timer that calls `run` every `interval`, `None` stops the timer.
+/// `new_state` gets the state of the replaced schedule. Traps when the memory-id is not registered.
+pub(crate) fn set_schedule_interval<S>(
+    schedules: &'static LocalKey<RefCell<TimerSchedules<S>>>,
+    memory_id: MemoryId,
+    opt_interval: Option<Duration>,
+    run: fn(MemoryId),
+    new_state: impl FnOnce(Option<S>) -> S,
+) {
+    let id: u8 = registered_memory_id(memory_id).unwrap_or_else(|| {
+        trap(&format!("memory-id: {:?} is not registered with the canister-tools library.", memory_id))
+    });
+    with_mut(schedules, |schedules| {
+        let opt_old_state: Option<S> = schedules.remove(&memory_id).map(|old_schedule| {
+            clear_timer(old_schedule.timer_id);
+            old_schedule.state
+        });
+        if let Some(interval) = opt_interval {
+            schedules.insert(
+                memory_id,
+                TimerSchedule {
+                    memory_id: id,
+                    interval,
+                    timer_id: set_timer_interval(interval, move || run(memory_id)),
+                    state: new_state(opt_old_state),
+                }
+            );
+        }
+    });
+}
+
+/// Records the result of a timer run in the state of the schedule, when the schedule was not stopped during the run.
+pub(crate) fn update_schedule_state<S>(schedules: &'static LocalKey<RefCell<TimerSchedules<S>>>, memory_id: MemoryId, f: impl FnOnce(&mut S)) {
+    with_mut(schedules, |schedules| {
+        if let Some(schedule) = schedules.get_mut(&memory_id) {
+            f(&mut schedule.state);
+        }
+    });
+}
@@ -0,0 +1,224 @@
+// The upload sessions of the controller_begin_state_snapshot_upload canister method.
+// A session holds the snapshot buffer of a memory-id until the session is loaded, canceled, or expires.
+// The chunks are written at their offsets, so a chunk that is written twice is harmless and the chunks can arrive in any order.
+// A staged session writes the chunks into the staging area in the stable memory instead of the snapshot buffer, see upload_staging.rs.
+
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use candid::{CandidType, Deserialize};
+
+
+const DEFAULT_UPLOAD_SESSION_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+thread_local!{
+    // the session-ids start at the time of the session, so that a session-id from before an upgrade is not reused after the upgrade.
+    static NEXT_UPLOAD_SESSION_ID: Cell<u64> = const { Cell::new(0) };
+    static UPLOAD_SESSION_TIMEOUT: Cell<Duration> = const { Cell::new(DEFAULT_UPLOAD_SESSION_TIMEOUT) };
+}
+
+/// Sets the time that an upload session of the `controller_begin_state_snapshot_upload` canister method
+/// stays open after its last chunk. The default is one hour.
+///
+/// An expired session is dropped and its buffer is freed the next time a controller method uses the memory-id of the session,
+/// or by a timer when the `timers` cargo feature is enabled.
+pub fn set_upload_session_timeout(timeout: Duration) {
+    UPLOAD_SESSION_TIMEOUT.with(|t| t.set(timeout));
+}
+
+/// A range of the bytes of an upload session.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ByteRange {
+    pub offset: u64,
+    pub length: u64,
+}
+
+pub(crate) struct UploadSession {
+    pub(crate) session_id: u64,
+    pub(crate) total_length: u64,
+    pub(crate) sha256: [u8; 32],
+    pub(crate) expires_at_nanos: u64,
+    // the chunks are written into the staging area in the stable memory.
+    pub(crate) staged: bool,
+    // the sha256 of the bytes, set when all of the bytes arrive.
+    pub(crate) received_sha256: Option<[u8; 32]>,
+    // the received byte ranges, start -> end, merged when they overlap or touch.
+    received: BTreeMap<u64, u64>,
+}
+
+impl UploadSession {
+    pub(crate) fn new(total_length: u64, sha256: [u8; 32], staged: bool, now_nanos: u64) -> Self {
+        let session_id: u64 = NEXT_UPLOAD_SESSION_ID.with(|next| {
+            let session_id: u64 = next.get().max(now_nanos);
+            next.set(session_id + 1);
+            session_id
+        });
+        let mut upload_session = Self {
+            session_id,
+            total_length,
+            sha256,
+            expires_at_nanos: 0,
+            staged,
+            received_sha256: None,
+            received: BTreeMap::new(),
+        };
+        upload_session.touch(now_nanos);
+        upload_session
+    }
+
+    /// Extends the session by the timeout from now.
+    pub(crate) fn touch(&mut self, now_nanos: u64) {
+        self.expires_at_nanos = now_nanos.saturating_add(UPLOAD_SESSION_TIMEOUT.with(|t| t.get()).as_nanos() as u64);
+    }
+
+    pub(crate) fn is_expired(&self, now_nanos: u64) -> bool {
+        now_nanos >= self.expires_at_nanos
+    }
+
+    /// Records the bytes `offset..end` as received.
+    pub(crate) fn receive(&mut self, offset: u64, end: u64) {
+        let (mut start, mut end) = (offset, end);
+        let touching: Vec<(u64, u64)> = self.received.range(..=end)
+            .filter(|(_, range_end)| **range_end >= start)
+            .map(|(range_start, range_end)| (*range_start, *range_end))
+            .collect();
+        for (range_start, range_end) in touching {
+            self.received.remove(&range_start);
+            start = start.min(range_start);
+            end = end.max(range_end);
+        }
+        self.received.insert(start, end);
+    }
+
+    pub(crate) fn received_length(&self) -> u64 {
+        self.received.iter().map(|(start, end)| end - start).sum()
+    }
+
+    pub(crate) fn is_complete(&self) -> bool {
+        self.received_length() == self.total_length
+    }
+
+    pub(crate) fn missing_ranges(&self) -> Vec<ByteRange> {
+        let mut missing_ranges: Vec<ByteRange> = Vec::new();
+        let mut position: u64 = 0;
+        for (start, end) in self.received.iter() {
+            if *start > position {
+                missing_ranges.push(ByteRange{ offset: position, length: start - position });
+            }
+            position = *end;
+        }
+        if self.total_length > position {
+            missing_ranges.push(ByteRange{ offset: position, length: self.total_length - position });
+        }
+        missing_ranges
+    }
+
+    // The staged sessions are stored with the staged bytes so that they continue after an upgrade.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut b: Vec<u8> = Vec::with_capacity(93 + self.received.len() * 16);
+        b.extend_from_slice(&self.session_id.to_be_bytes());
+        b.extend_from_slice(&self.total_length.to_be_bytes());
+        b.extend_from_slice(&self.sha256);
+        b.extend_from_slice(&self.expires_at_nanos.to_be_bytes());
+        b.push(self.received_sha256.is_some() as u8);
+        b.extend_from_slice(&self.received_sha256.unwrap_or_default());
+        b.extend_from_slice(&(self.received.len() as u32).to_be_bytes());
+        for (start, end) in self.received.iter() {
+            b.extend_from_slice(&start.to_be_bytes());
+            b.extend_from_slice(&end.to_be_bytes());
+        }
+        b
+    }
+
+    // Decodes a staged session, the next session-ids continue after its session-id.
+    pub(crate) fn decode(b: &[u8]) -> Self {
+        let u64_at = |i: usize| u64::from_be_bytes(b[i..i + 8].try_into().unwrap());
+        let range_count: usize = u32::from_be_bytes(b[89..93].try_into().unwrap()) as usize;
+        let upload_session = Self {
+            session_id: u64_at(0),
+            total_length: u64_at(8),
+            sha256: b[16..48].try_into().unwrap(),
+            expires_at_nanos: u64_at(48),
+            staged: true,
+            received_sha256: if b[56] == 1 { Some(b[57..89].try_into().unwrap()) } else { None },
+            received: (0..range_count).map(|i| (u64_at(93 + i * 16), u64_at(93 + i * 16 + 8))).collect(),
+        };
+        NEXT_UPLOAD_SESSION_ID.with(|next| next.set(next.get().max(upload_session.session_id + 1)));
+        upload_session
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn received_ranges(upload_session: &UploadSession) -> Vec<(u64, u64)> {
+        upload_session.received.iter().map(|(start, end)| (*start, *end)).collect()
+    }
+
+    #[test]
+    fn receive_merges_overlapping_ranges() {
+        let mut upload_session = UploadSession::new(100, [0; 32], false, 0);
+        upload_session.receive(10, 30);
+        upload_session.receive(20, 40);
+        upload_session.receive(5, 15);
+        assert_eq!(received_ranges(&upload_session), vec![(5, 40)]);
+        upload_session.receive(0, 100);
+        assert_eq!(received_ranges(&upload_session), vec![(0, 100)]);
+        assert!(upload_session.is_complete());
+    }
+
+    #[test]
+    fn receive_merges_touching_ranges() {
+        let mut upload_session = UploadSession::new(30, [0; 32], false, 0);
+        upload_session.receive(0, 10);
+        upload_session.receive(20, 30);
+        assert_eq!(received_ranges(&upload_session), vec![(0, 10), (20, 30)]);
+        upload_session.receive(10, 20);
+        assert_eq!(received_ranges(&upload_session), vec![(0, 30)]);
+        assert_eq!(upload_session.received_length(), 30);
+    }
+
+    #[test]
+    fn missing_ranges_between_the_received_ranges() {
+        let mut upload_session = UploadSession::new(100, [0; 32], false, 0);
+        assert_eq!(upload_session.missing_ranges(), vec![ByteRange{ offset: 0, length: 100 }]);
+        upload_session.receive(10, 20);
+        upload_session.receive(50, 60);
+        assert_eq!(upload_session.missing_ranges(), vec![
+            ByteRange{ offset: 0, length: 10 },
+            ByteRange{ offset: 20, length: 30 },
+            ByteRange{ offset: 60, length: 40 },
+        ]);
+        // a chunk that is written twice is received once.
+        upload_session.receive(10, 20);
+        assert_eq!(upload_session.received_length(), 20);
+        assert!(!upload_session.is_complete());
+    }
+
+    #[test]
+    fn session_ids_start_at_the_time() {
+        let first = UploadSession::new(1, [0; 32], false, 1_000);
+        let second = UploadSession::new(1, [0; 32], false, 1_000);
+        assert!(first.session_id >= 1_000);
+        assert!(second.session_id > first.session_id);
+    }
+
+    #[test]
+    fn encode_decode_a_staged_session() {
+        let mut upload_session = UploadSession::new(100, [7; 32], true, 5);
+        upload_session.receive(0, 10);
+        upload_session.receive(40, 100);
+        upload_session.received_sha256 = Some([8; 32]);
+        let decoded = UploadSession::decode(&upload_session.encode());
+        assert_eq!(
+            (decoded.session_id, decoded.total_length, decoded.sha256, decoded.expires_at_nanos, decoded.staged, decoded.received_sha256),
+            (upload_session.session_id, 100, [7; 32], upload_session.expires_at_nanos, true, Some([8; 32]))
+        );
+        assert_eq!(received_ranges(&decoded), vec![(0, 10), (40, 100)]);
+        // the next session-ids continue after the decoded session-id.
+        assert!(UploadSession::new(1, [0; 32], false, 0).session_id > decoded.session_id);
+    }
+}
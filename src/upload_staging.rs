@@ -0,0 +1,124 @@
+// The staging area of the upload sessions, stored in a StableBTreeMap in the reserved UPLOAD_STAGING_MEMORY_ID
+// so that a staged upload does not grow the heap and continues after an upgrade.
+//
+// The staged bytes of a memory-id are stored in blocks keyed by the memory-id and the block index,
+// and the upload session of the memory-id is stored at the block index u64::MAX.
+
+use std::cell::RefCell;
+use std::io;
+
+use ic_stable_structures::{
+    StableBTreeMap,
+    DefaultMemoryImpl,
+    memory_manager::VirtualMemory,
+};
+
+use crate::localkey::refcell::{with, with_mut};
+use crate::{Compression, UPLOAD_STAGING_MEMORY_ID, get_virtual_memory};
+use crate::upload_sessions::UploadSession;
+
+
+const BLOCK_SIZE: u64 = 64 * 1024;
+
+const SESSION_BLOCK_INDEX: u64 = u64::MAX;
+
+
+type StagedBlocks = StableBTreeMap<(u8, u64), Vec<u8>, VirtualMemory<DefaultMemoryImpl>>;
+
+thread_local!{
+    static STAGED_BLOCKS: RefCell<StagedBlocks> = RefCell::new(StagedBlocks::init(get_virtual_memory(UPLOAD_STAGING_MEMORY_ID)));
+}
+
+
+/// Stores the upload session and the compression of the staged bytes of the memory-id.
+pub(crate) fn store_staged_session(id: u8, compression: Compression, upload_session: &UploadSession) {
+    let mut b: Vec<u8> = vec![compression.id()];
+    b.extend_from_slice(&upload_session.encode());
+    with_mut(&STAGED_BLOCKS, |staged_blocks| {
+        staged_blocks.insert((id, SESSION_BLOCK_INDEX), b);
+    });
+}
+
+/// The stored upload session of the memory-id, and the compression of its staged bytes.
+pub(crate) fn get_staged_session(id: u8) -> Option<(Compression, UploadSession)> {
+    with(&STAGED_BLOCKS, |staged_blocks| {
+        staged_blocks.get(&(id, SESSION_BLOCK_INDEX)).map(|b| {
+            (
+                Compression::from_id(b[0]).unwrap_or_else(|| ic_cdk::trap(&format!("unknown compression id: {}", b[0]))),
+                UploadSession::decode(&b[1..]),
+            )
+        })
+    })
+}
+
+/// Writes the bytes at the offset of the staged bytes of the memory-id.
+pub(crate) fn write_staged(id: u8, offset: u64, bytes: &[u8]) {
+    with_mut(&STAGED_BLOCKS, |staged_blocks| {
+        let mut position: u64 = offset;
+        let end: u64 = offset + bytes.len() as u64;
+        while position < end {
+            let block_index: u64 = position / BLOCK_SIZE;
+            let block_start: u64 = block_index * BLOCK_SIZE;
+            let write_end: u64 = std::cmp::min(end, block_start + BLOCK_SIZE);
+            // a whole block is written without the read.
+            let mut block: Vec<u8> = match position == block_start && write_end == block_start + BLOCK_SIZE {
+                true => vec![0; BLOCK_SIZE as usize],
+                false => staged_blocks.get(&(id, block_index)).unwrap_or_else(|| vec![0; BLOCK_SIZE as usize]),
+            };
+            block[((position - block_start) as usize)..((write_end - block_start) as usize)]
+                .copy_from_slice(&bytes[((position - offset) as usize)..((write_end - offset) as usize)]);
+            staged_blocks.insert((id, block_index), block);
+            position = write_end;
+        }
+    });
+}
+
+/// Removes the staged bytes and the upload session of the memory-id.
+pub(crate) fn clear_staged(id: u8) {
+    with_mut(&STAGED_BLOCKS, |staged_blocks| {
+        let keys: Vec<(u8, u64)> = staged_blocks.keys_range((id, 0)..=(id, u64::MAX)).collect();
+        for key in keys {
+            staged_blocks.remove(&key);
+        }
+    });
+}
+
+/// A [Read](std::io::Read) adapter over the first `length` staged bytes of a memory-id.
+pub(crate) struct StagedReader {
+    memory_id: u8,
+    position: u64,
+    length: u64,
+    // the block at the position and its index.
+    block: Option<(u64, Vec<u8>)>,
+}
+
+impl StagedReader {
+    pub(crate) fn new(id: u8, length: u64) -> Self {
+        Self {
+            memory_id: id,
+            position: 0,
+            length,
+            block: None,
+        }
+    }
+}
+
+impl io::Read for StagedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.length || buf.is_empty() {
+            return Ok(0);
+        }
+        let block_index: u64 = self.position / BLOCK_SIZE;
+        if self.block.as_ref().is_none_or(|(i, _)| *i != block_index) {
+            let block: Vec<u8> = with(&STAGED_BLOCKS, |staged_blocks| staged_blocks.get(&(self.memory_id, block_index)))
+                .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, format!("the staged block {} is missing", block_index)))?;
+            self.block = Some((block_index, block));
+        }
+        let block: &[u8] = &self.block.as_ref().unwrap().1;
+        let block_offset: usize = (self.position - block_index * BLOCK_SIZE) as usize;
+        let n: usize = (buf.len() as u64).min((block.len() - block_offset) as u64).min(self.length - self.position) as usize;
+        buf[..n].copy_from_slice(&block[block_offset..block_offset + n]);
+        self.position += n as u64;
+        Ok(n)
+    }
+}
@@ -15,6 +15,7 @@ use canister_tools::{
 
 
 const DATA_UPGRADE_SERIALIZATION_MEMORY_ID: MemoryId = MemoryId::new(0);
+const STAGED_DATA_UPGRADE_SERIALIZATION_MEMORY_ID: MemoryId = MemoryId::new(1);
 
 
 #[derive(CandidType, Deserialize)]
@@ -31,11 +32,20 @@ thread_local! {
             field_two: 55
         }
     );
+    // the uploads of this global variable are staged in the stable memory.
+    static STAGED_DATA: RefCell<Data> = RefCell::new(
+        Data{
+            field_one: String::from("Hi World"),
+            field_two: 55
+        }
+    );
 }
 
 #[init]
 fn init() {
     canister_tools::init(&DATA, DATA_UPGRADE_SERIALIZATION_MEMORY_ID);
+    canister_tools::init(&STAGED_DATA, STAGED_DATA_UPGRADE_SERIALIZATION_MEMORY_ID);
+    canister_tools::set_upload_staging(STAGED_DATA_UPGRADE_SERIALIZATION_MEMORY_ID, true);
 }
 
 #[pre_upgrade]
@@ -46,6 +56,8 @@ fn pre_upgrade() {
 #[post_upgrade]
 fn post_upgrade() {
     canister_tools::post_upgrade(&DATA, DATA_UPGRADE_SERIALIZATION_MEMORY_ID, None::<fn(Data) -> Data>);
+    canister_tools::post_upgrade(&STAGED_DATA, STAGED_DATA_UPGRADE_SERIALIZATION_MEMORY_ID, None::<fn(Data) -> Data>);
+    canister_tools::set_upload_staging(STAGED_DATA_UPGRADE_SERIALIZATION_MEMORY_ID, true);
 }
 
 
@@ -63,3 +75,10 @@ pub fn set_field_two(value: u64) {
         data.field_two = value;
     });
 }
+
+#[query]
+pub fn get_staged_field_two() -> u64 {
+    with(&STAGED_DATA, |data| {
+        data.field_two
+    })
+}
@@ -7,12 +7,18 @@ import 'package:ic_tools/ic_tools.dart';
 import 'package:ic_tools/common.dart';
 import 'package:ic_tools/tools.dart';
 import 'package:ic_tools/candid.dart';
+import 'package:crypto/crypto.dart';
 
 
 
 const int data_upgrade_serialization_memory_id = 0;
+// the uploads of this memory-id are staged in the stable memory.
+const int staged_data_upgrade_serialization_memory_id = 1;
 
-const int chunk_size = 1024 * 512 * 3;
+const int upload_chunk_size = 1024 * 512 * 3;
+
+// splits the small test snapshots into more than one chunk.
+const int small_chunk_size = 16;
 
 Caller caller = Caller(keys: Ed25519Keys.new_keys());
 
@@ -31,11 +37,17 @@ Future<void> main(List<String> arguments) async {
         CanisterInstallMode.install
     );
         
-    Uint8List state_snapshot = await create_and_download_state_snapshot(canister_id);
+    Uint8List state_snapshot = await create_and_download_state_snapshot(canister_id, data_upgrade_serialization_memory_id);
+    
+    Uint8List state_snapshot_sha256 = await get_state_snapshot_sha256(canister_id, data_upgrade_serialization_memory_id);
+    
+    await load_state_snapshot(canister_id, data_upgrade_serialization_memory_id, state_snapshot);
     
-    await load_state_snapshot(canister_id, state_snapshot);
+    if (aresamebytes(state_snapshot_sha256, await get_state_snapshot_sha256(canister_id, data_upgrade_serialization_memory_id)) == false) {
+        throw Exception('check this');
+    }
     
-    Uint8List state_snapshot_2 = await create_and_download_state_snapshot(canister_id);
+    Uint8List state_snapshot_2 = await create_and_download_state_snapshot(canister_id, data_upgrade_serialization_memory_id);
     
     if (aresamebytes(state_snapshot, state_snapshot_2) == false) {
         throw Exception('check this');
@@ -62,7 +74,7 @@ Future<void> main(List<String> arguments) async {
     int new_field_two = 54;
     state_snapshot_backwards['field_two'] = Nat64(BigInt.from(new_field_two));
     
-    await load_state_snapshot(canister_id, c_forwards_one(state_snapshot_backwards));
+    await load_state_snapshot(canister_id, data_upgrade_serialization_memory_id, c_forwards_one(state_snapshot_backwards));
     
     get_field_two = (c_backwards_one(await Canister(canister_id).call(
         calltype: CallType.query,
@@ -80,7 +92,7 @@ Future<void> main(List<String> arguments) async {
         put_bytes: c_forwards_one(Nat64(BigInt.from(53)))
     ); 
     
-    Uint8List snapshot = await create_and_download_state_snapshot(canister_id);
+    Uint8List snapshot = await create_and_download_state_snapshot(canister_id, data_upgrade_serialization_memory_id);
     
     if (((c_backwards_one(snapshot) as Record)['field_two'] as Nat64).value.toInt() != 53) {
         throw Exception('check this');
@@ -93,81 +105,340 @@ Future<void> main(List<String> arguments) async {
         CanisterInstallMode.upgrade
     );
     
-    if (aresamebytes(snapshot, await create_and_download_state_snapshot(canister_id)) == false) {
+    if (aresamebytes(snapshot, await create_and_download_state_snapshot(canister_id, data_upgrade_serialization_memory_id)) == false) {
+        throw Exception('check this');
+    }
+    
+    // the download with controller_download_state_snapshot.
+    int snapshot_length = await create_state_snapshot(canister_id, data_upgrade_serialization_memory_id);
+    if (aresamebytes(snapshot, await download_state_snapshot(canister_id, data_upgrade_serialization_memory_id, snapshot_length)) == false) {
+        throw Exception('check this');
+    }
+    
+    // the upload with controller_append_state_snapshot.
+    int append_field_two = 51;
+    Record append_snapshot_backwards = c_backwards_one(snapshot) as Record;
+    append_snapshot_backwards['field_two'] = Nat64(BigInt.from(append_field_two));
+    Uint8List append_snapshot = c_forwards_one(append_snapshot_backwards);
+    
+    await append_state_snapshot(canister_id, data_upgrade_serialization_memory_id, append_snapshot);
+    
+    if (aresamebytes(sha256.convert(append_snapshot).bytes, await get_state_snapshot_sha256(canister_id, data_upgrade_serialization_memory_id)) == false) {
+        throw Exception('check this');
+    }
+    
+    controller_result_ok(await Canister(canister_id).call(
+        calltype: CallType.call,
+        method_name: 'controller_load_state_snapshot',
+        put_bytes: c_forwards_one(Nat8(data_upgrade_serialization_memory_id)),
+        caller: caller
+    ));
+    
+    if (await query_field_two(canister_id, 'get_field_two') != append_field_two) {
+        throw Exception('check this');
+    }
+    
+    // the idempotent upload with controller_put_state_snapshot_chunk, the chunks arrive out of order.
+    int put_field_two = 50;
+    Record put_snapshot_backwards = c_backwards_one(snapshot) as Record;
+    put_snapshot_backwards['field_two'] = Nat64(BigInt.from(put_field_two));
+    List<Uint8List> put_chunks = c_forwards_one(put_snapshot_backwards).chunks(small_chunk_size);
+    
+    await put_state_snapshot_chunks(canister_id, data_upgrade_serialization_memory_id, put_chunks, [for (int i = put_chunks.length - 1; i >= 1; i--) i]);
+    
+    Record upload_status = (controller_result_ok(await Canister(canister_id).call(
+        calltype: CallType.query,
+        method_name: 'controller_state_snapshot_upload_status',
+        put_bytes: c_forwards_one(Nat8(data_upgrade_serialization_memory_id)),
+        caller: caller
+    )) as Option).value as Record;
+    
+    if ((upload_status['missing_chunks'] as Vector).map((chunk_index) => (chunk_index as Nat32).value).toList().toString() != [0].toString()) {
+        throw Exception('check this');
+    }
+    
+    // the load waits for the missing chunk.
+    if ((c_backwards_one(await Canister(canister_id).call(
+        calltype: CallType.call,
+        method_name: 'controller_load_state_snapshot',
+        put_bytes: c_forwards_one(Nat8(data_upgrade_serialization_memory_id)),
+        caller: caller
+    )) as Variant).containsKey('Err') == false) {
+        throw Exception('check this');
+    }
+    
+    await put_state_snapshot_chunks(canister_id, data_upgrade_serialization_memory_id, put_chunks, [0]);
+    // a retry of a chunk that already arrived.
+    await put_state_snapshot_chunks(canister_id, data_upgrade_serialization_memory_id, put_chunks, [0]);
+    
+    controller_result_ok(await Canister(canister_id).call(
+        calltype: CallType.call,
+        method_name: 'controller_load_state_snapshot',
+        put_bytes: c_forwards_one(Nat8(data_upgrade_serialization_memory_id)),
+        caller: caller
+    ));
+    
+    if (await query_field_two(canister_id, 'get_field_two') != put_field_two) {
+        throw Exception('check this');
+    }
+    
+    // the status of a heap upload session, and its cancel.
+    BigInt heap_session_id = await begin_state_snapshot_upload(canister_id, data_upgrade_serialization_memory_id, snapshot);
+    await upload_state_snapshot_chunks(canister_id, heap_session_id, snapshot.sublist(0, snapshot.length ~/ 2), 0);
+    
+    Record heap_session = controller_result_ok(await Canister(canister_id).call(
+        calltype: CallType.query,
+        method_name: 'controller_state_snapshot_upload_session',
+        put_bytes: c_forwards_one(Nat64(heap_session_id)),
+        caller: caller
+    )) as Record;
+    
+    if ((heap_session['received_length'] as Nat64).value.toInt() != snapshot.length ~/ 2) {
+        throw Exception('check this');
+    }
+    
+    Record missing_range = (heap_session['missing_ranges'] as Vector).single as Record;
+    if ((missing_range['offset'] as Nat64).value.toInt() != snapshot.length ~/ 2 
+    || (missing_range['length'] as Nat64).value.toInt() != snapshot.length - snapshot.length ~/ 2) {
+        throw Exception('check this');
+    }
+    
+    controller_result_ok(await Canister(canister_id).call(
+        calltype: CallType.call,
+        method_name: 'controller_cancel_state_snapshot_upload',
+        put_bytes: c_forwards_one(Nat64(heap_session_id)),
+        caller: caller
+    ));
+    
+    if ((c_backwards_one(await Canister(canister_id).call(
+        calltype: CallType.query,
+        method_name: 'controller_state_snapshot_upload_session',
+        put_bytes: c_forwards_one(Nat64(heap_session_id)),
+        caller: caller
+    )) as Variant).containsKey('Err') == false) {
+        throw Exception('check this');
+    }
+    
+    // a staged upload session continues after an upgrade.
+    int staged_field_two = 52;
+    Record staged_snapshot_backwards = c_backwards_one(snapshot) as Record;
+    staged_snapshot_backwards['field_two'] = Nat64(BigInt.from(staged_field_two));
+    Uint8List staged_snapshot = c_forwards_one(staged_snapshot_backwards);
+    
+    BigInt staged_session_id = await begin_state_snapshot_upload(canister_id, staged_data_upgrade_serialization_memory_id, staged_snapshot);
+    await upload_state_snapshot_chunks(canister_id, staged_session_id, staged_snapshot.sublist(0, staged_snapshot.length ~/ 2), 0);
+    
+    await put_code_on_the_canister(
+        caller,
+        canister_id,
+        File('../canister/target/wasm32-unknown-unknown/release/test_canister.wasm').readAsBytesSync(),
+        CanisterInstallMode.upgrade
+    );
+    
+    await upload_state_snapshot_chunks(canister_id, staged_session_id, staged_snapshot.sublist(staged_snapshot.length ~/ 2), staged_snapshot.length ~/ 2);
+    
+    if (aresamebytes(sha256.convert(staged_snapshot).bytes, await get_state_snapshot_sha256(canister_id, staged_data_upgrade_serialization_memory_id)) == false) {
         throw Exception('check this');
     }
     
+    controller_result_ok(await Canister(canister_id).call(
+        calltype: CallType.call,
+        method_name: 'controller_load_state_snapshot',
+        put_bytes: c_forwards_one(Nat8(staged_data_upgrade_serialization_memory_id)),
+        caller: caller
+    ));
     
+    int get_staged_field_two = (c_backwards_one(await Canister(canister_id).call(
+        calltype: CallType.query,
+        method_name: 'get_staged_field_two',
+    )) as Nat64).value.toInt(); 
     
+    if (get_staged_field_two != staged_field_two) {
+        throw Exception('check this');
+    }
+    
+    // the staged bytes are freed with the load, there is no snapshot left.
+    if (aresamebytes(sha256.convert([]).bytes, await get_state_snapshot_sha256(canister_id, staged_data_upgrade_serialization_memory_id)) == false) {
+        throw Exception('check this');
+    }
     
 }
 
 
 
-Future<Uint8List> create_and_download_state_snapshot(Principal canister_id) async {
-    
-    int snapshot_length = (c_backwards_one(await Canister(canister_id).call(
+// Returns the length of the snapshot.
+Future<int> create_state_snapshot(Principal canister_id, int memory_id) async {
+    return ((controller_result_ok(await Canister(canister_id).call(
         method_name: 'controller_create_state_snapshot',
         calltype: CallType.call,
-        put_bytes: c_forwards_one(Nat8(data_upgrade_serialization_memory_id)),    
+        put_bytes: c_forwards_one(Nat8(memory_id)),    
         caller:caller,
-    )) as Nat64).value.toInt();
+    )) as Record)[0] as Nat64).value.toInt();
+}
+
+Future<Uint8List> create_and_download_state_snapshot(Principal canister_id, int memory_id) async {
+    
+    int snapshot_length = await create_state_snapshot(canister_id, memory_id);
     
     List<int> snapshot = [];
     while (snapshot.length < snapshot_length) {
-        snapshot.addAll(
-            (c_backwards_one(await Canister(canister_id).call(
-                method_name: 'controller_download_state_snapshot',
-                caller:caller,
-                calltype: CallType.query,
-                put_bytes: c_forwards([
-                    Nat8(data_upgrade_serialization_memory_id),
-                    Nat64(BigInt.from(snapshot.length)),
-                    Nat64(BigInt.from(min(chunk_size, snapshot_length - snapshot.length)))
-                ])
-            )) as Blob).bytes    
-        );
+        // the canister clamps the chunk to the bytes that fit in one reply.
+        Record chunk = controller_result_ok(await Canister(canister_id).call(
+            method_name: 'controller_download_state_snapshot_chunk',
+            caller:caller,
+            calltype: CallType.query,
+            put_bytes: c_forwards([
+                Nat8(memory_id),
+                Nat64(BigInt.from(snapshot.length)),
+                Nat64(BigInt.from(snapshot_length - snapshot.length))
+            ])
+        )) as Record;
+        snapshot.addAll((chunk['bytes'] as Blob).bytes);
+        if ((chunk['next_offset'] as Nat64).value.toInt() != snapshot.length) {
+            throw Exception('check this');
+        }
     }
     
     return Uint8List.fromList(snapshot); 
 
 }
 
-Future<void> load_state_snapshot(Principal canister_id, Uint8List load_snapshot) async {
+Future<Uint8List> download_state_snapshot(Principal canister_id, int memory_id, int snapshot_length) async {
+    List<int> snapshot = [];
+    while (snapshot.length < snapshot_length) {
+        snapshot.addAll((controller_result_ok(await Canister(canister_id).call(
+            method_name: 'controller_download_state_snapshot',
+            caller:caller,
+            calltype: CallType.query,
+            put_bytes: c_forwards([
+                Nat8(memory_id),
+                Nat64(BigInt.from(snapshot.length)),
+                Nat64(BigInt.from(min(small_chunk_size, snapshot_length - snapshot.length)))
+            ])
+        )) as Blob).bytes);
+    }
+    return Uint8List.fromList(snapshot);
+}
+
+Future<Uint8List> get_state_snapshot_sha256(Principal canister_id, int memory_id) async {
+    return Uint8List.fromList((controller_result_ok(await Canister(canister_id).call(
+        method_name: 'controller_state_snapshot_sha256',
+        calltype: CallType.query,
+        put_bytes: c_forwards_one(Nat8(memory_id)),
+        caller:caller,
+    )) as Blob).bytes);
+}
+
+Future<BigInt> begin_state_snapshot_upload(Principal canister_id, int memory_id, Uint8List load_snapshot) async {
+    return (controller_result_ok(await Canister(canister_id).call(
+        calltype: CallType.call,
+        method_name: 'controller_begin_state_snapshot_upload',
+        put_bytes: c_forwards([
+            Nat8(memory_id),
+            Nat64(BigInt.from(load_snapshot.length)),
+            Blob(sha256.convert(load_snapshot).bytes),
+            Variant.of_the_map({'None': Null()})
+        ]),
+        caller: caller
+    )) as Nat64).value;
+}
+
+// Uploads the bytes at the offset of the upload session.
+Future<void> upload_state_snapshot_chunks(Principal canister_id, BigInt session_id, Uint8List bytes, int offset) async {
+    List<Uint8List> chunks = bytes.chunks(upload_chunk_size);
+    await Future.wait([
+        for (int i = 0; i < chunks.length; i++) 
+            Canister(canister_id).call(
+                calltype: CallType.call,
+                method_name: 'controller_upload_state_snapshot_chunk',
+                put_bytes: c_forwards([
+                    Nat64(session_id),
+                    Nat64(BigInt.from(offset + i * upload_chunk_size)),
+                    Blob(chunks[i])
+                ]),
+                caller: caller
+            ).then(controller_result_ok)
+    ]);
+}
+
+Future<void> load_state_snapshot(Principal canister_id, int memory_id, Uint8List load_snapshot) async {
     
-    await Canister(canister_id).call(
+    BigInt session_id = await begin_state_snapshot_upload(canister_id, memory_id, load_snapshot);
+    
+    await upload_state_snapshot_chunks(canister_id, session_id, load_snapshot, 0);
+    
+    controller_result_ok(await Canister(canister_id).call(
+        calltype: CallType.call,
+        method_name: 'controller_load_state_snapshot',
+        put_bytes: c_forwards_one(Nat8(memory_id)),
+        caller: caller
+    ));
+       
+}
+
+
+
+
+
+
+// Uploads the snapshot with controller_clear_state_snapshot and controller_append_state_snapshot.
+Future<void> append_state_snapshot(Principal canister_id, int memory_id, Uint8List load_snapshot) async {
+    controller_result_ok(await Canister(canister_id).call(
         calltype: CallType.call,
         method_name: 'controller_clear_state_snapshot',
-        put_bytes: c_forwards_one(Nat8(data_upgrade_serialization_memory_id)),
+        put_bytes: c_forwards_one(Nat8(memory_id)),
         caller: caller
-    );
-    
-    List<Uint8List> chunks = load_snapshot.chunks(chunk_size);
-    for (Uint8List chunk in chunks) {
-        await Canister(canister_id).call(
+    ));
+    for (Uint8List chunk in load_snapshot.chunks(small_chunk_size)) {
+        controller_result_ok(await Canister(canister_id).call(
             calltype: CallType.call,
             method_name: 'controller_append_state_snapshot',
             put_bytes: c_forwards([
-                Nat8(data_upgrade_serialization_memory_id),
+                Nat8(memory_id),
                 Blob(chunk)
             ]),
             caller: caller
-        );
+        ));
     }
-    
-    await Canister(canister_id).call(
-        calltype: CallType.call,
-        method_name: 'controller_load_state_snapshot',
-        put_bytes: c_forwards_one(Nat8(data_upgrade_serialization_memory_id)),
-        caller: caller
-    );
-       
 }
 
+// Puts the chunks at the indexes, in parallel.
+Future<void> put_state_snapshot_chunks(Principal canister_id, int memory_id, List<Uint8List> chunks, List<int> chunk_indexes) async {
+    await Future.wait([
+        for (int chunk_index in chunk_indexes) 
+            Canister(canister_id).call(
+                calltype: CallType.call,
+                method_name: 'controller_put_state_snapshot_chunk',
+                put_bytes: c_forwards([
+                    Nat8(memory_id),
+                    Nat32(chunk_index),
+                    Nat32(chunks.length),
+                    Blob(chunks[chunk_index])
+                ]),
+                caller: caller
+            ).then(controller_result_ok)
+    ]);
+}
 
+Future<int> query_field_two(Principal canister_id, String method_name) async {
+    return (c_backwards_one(await Canister(canister_id).call(
+        calltype: CallType.query,
+        method_name: method_name,
+    )) as Nat64).value.toInt();
+}
 
 
 
+// Returns the Ok value of the reply of a controller method, throws on the Err variant with the ControllerError.
+CandidType controller_result_ok(Uint8List reply) {
+    Variant result = c_backwards_one(reply) as Variant;
+    if (result.containsKey('Err')) {
+        throw Exception('controller method error: ${result['Err']}');
+    }
+    return result['Ok']!;
+}
+
+
 
 extension Chunks<T extends List> on T {
     List<T> chunks(int chunk_size) {